@@ -0,0 +1,237 @@
+//! `bf!`, which turns a BF program into a Rust function at compile time.
+use std::path::Path;
+
+use bf_interpreter::{
+    bytecode_bf::{ByteCode, ByteCodeProgram, Change},
+    parser::Parser,
+};
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{
+    parse::{Parse, ParseStream},
+    Attribute, Ident, LitStr, Token, Visibility,
+};
+
+/// Defines a function running a BF program, given inline or as a path
+/// relative to the manifest directory of the crate using it:
+///
+/// ```ignore
+/// bf_macros::bf! {
+///     /// Echoes its input.
+///     pub fn cat = ",[.,]";
+/// }
+/// bf_macros::bf!(fn hello_world = include!("programs/hello_world.bf"));
+/// ```
+///
+/// Each becomes a
+///
+/// ```ignore
+/// pub fn cat(
+///     tape: &mut [u8],
+///     input: &mut impl std::io::Read,
+///     output: &mut impl std::io::Write,
+/// ) -> std::io::Result<()>
+/// ```
+///
+/// that starts at the first cell of `tape` and panics if it uses a cell off
+/// it.
+/// At the end of input `,` leaves the cell unchanged. Unmatched brackets
+/// are compile errors.
+#[proc_macro]
+pub fn bf(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let function = syn::parse_macro_input!(input as BfFunction);
+    expand(function)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// `<attributes> <visibility> fn <name> = <source>;`
+struct BfFunction {
+    attrs: Vec<Attribute>,
+    vis: Visibility,
+    name: Ident,
+    source: Source,
+}
+
+enum Source {
+    Inline(LitStr),
+    /// `include!("path")`
+    Include(LitStr),
+}
+
+impl Parse for BfFunction {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let vis = input.parse()?;
+        input.parse::<Token![fn]>()?;
+        let name = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let source = if input.peek(LitStr) {
+            Source::Inline(input.parse()?)
+        } else {
+            let mac: Ident = input.parse()?;
+            if mac != "include" {
+                return Err(syn::Error::new(
+                    mac.span(),
+                    "expected a string literal or include!(\"<path>\")",
+                ));
+            }
+            input.parse::<Token![!]>()?;
+            let path;
+            syn::parenthesized!(path in input);
+            Source::Include(path.parse()?)
+        };
+        input.parse::<Option<Token![;]>>()?;
+        Ok(BfFunction {
+            attrs,
+            vis,
+            name,
+            source,
+        })
+    }
+}
+
+fn expand(function: BfFunction) -> syn::Result<TokenStream> {
+    let (src, literal, included) = match &function.source {
+        Source::Inline(literal) => (literal.value(), literal, None),
+        Source::Include(literal) => {
+            let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+            let path = Path::new(&dir).join(literal.value());
+            let src = std::fs::read_to_string(&path).map_err(|e| {
+                syn::Error::new(
+                    literal.span(),
+                    format!("failed to read {}: {}", path.display(), e),
+                )
+            })?;
+            (src, literal, Some(path.to_string_lossy().into_owned()))
+        }
+    };
+
+    let mut prog = Parser::parse_to_bytecode(src);
+    if let Some(pc) = prog.unmatched_bracket() {
+        let bracket = match prog.instructions[pc] {
+            ByteCode::JZ => '[',
+            _ => ']',
+        };
+        let loc = prog.spans[pc].start;
+        return Err(syn::Error::new(
+            literal.span(),
+            format!("unmatched '{}' at {}:{}", bracket, loc.line, loc.col),
+        ));
+    }
+    prog.opt_pass_1();
+    prog.opt_pass_2();
+
+    let body = body(&prog);
+    // so that the crate is rebuilt when the file changes
+    let dependency = included.map(|path| {
+        quote!(
+            const _: &str = include_str!(#path);
+        )
+    });
+    let BfFunction {
+        attrs, vis, name, ..
+    } = function;
+    Ok(quote! {
+        #(#attrs)*
+        #vis fn #name(
+            tape: &mut [u8],
+            input: &mut impl ::std::io::Read,
+            output: &mut impl ::std::io::Write,
+        ) -> ::std::io::Result<()> {
+            #dependency
+            let _ = (&input, &output);
+            #[allow(unused_mut, unused_variables)]
+            let mut p: usize = 0;
+            #body
+            Ok(())
+        }
+    })
+}
+
+/// Statements running `prog`, which has matching brackets, with the data
+/// pointer in `p`.
+fn body(prog: &ByteCodeProgram) -> TokenStream {
+    // the statements of each loop we're in
+    let mut blocks = vec![TokenStream::new()];
+    for instr in &prog.instructions {
+        let statement = match *instr {
+            ByteCode::Nop => continue,
+            ByteCode::DataPointerIncr(n) => quote!(p += #n;),
+            ByteCode::DataPointerDecr(n) => quote!(p = p.wrapping_sub(#n);),
+            ByteCode::DataIncr(n) => {
+                let n = n as u8;
+                quote!(tape[p] = tape[p].wrapping_add(#n);)
+            }
+            ByteCode::DataDecr(n) => {
+                let n = n as u8;
+                quote!(tape[p] = tape[p].wrapping_sub(#n);)
+            }
+            ByteCode::Write => quote!(::std::io::Write::write_all(&mut *output, &[tape[p]])?;),
+            ByteCode::Read => quote! {{
+                let mut byte = [0u8];
+                match ::std::io::Read::read_exact(&mut *input, &mut byte) {
+                    Ok(()) => tape[p] = byte[0],
+                    Err(e) if e.kind() == ::std::io::ErrorKind::UnexpectedEof => {}
+                    Err(e) => return Err(e),
+                }
+            }},
+            ByteCode::JZ => {
+                blocks.push(TokenStream::new());
+                continue;
+            }
+            ByteCode::JNZ => {
+                let inner = blocks.pop().unwrap();
+                quote!(while tape[p] != 0 { #inner })
+            }
+            ByteCode::SETZERO => quote!(tape[p] = 0;),
+            ByteCode::MoveInStepUntilZero(Change::Incr(n)) => {
+                quote!(while tape[p] != 0 { p += #n; })
+            }
+            ByteCode::MoveInStepUntilZero(Change::Decr(n)) => {
+                quote!(while tape[p] != 0 { p = p.wrapping_sub(#n); })
+            }
+            ByteCode::MultiplyAdd(offset, factor) => quote! {
+                if tape[p] != 0 {
+                    let target = p.wrapping_add_signed(#offset);
+                    tape[target] = tape[target].wrapping_add(tape[p].wrapping_mul(#factor));
+                }
+            },
+        };
+        blocks.last_mut().unwrap().extend(statement);
+    }
+    blocks.pop().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand, BfFunction};
+
+    fn expand_str(code: &str) -> Result<String, String> {
+        syn::parse_str::<BfFunction>(code)
+            .and_then(expand)
+            .map(|tokens| tokens.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        assert_eq!(
+            expand_str(r#"fn f = "+\n[[-]""#),
+            Err(String::from("unmatched '[' at 2:1"))
+        );
+        assert_eq!(
+            expand_str(r#"fn f = "+]""#),
+            Err(String::from("unmatched ']' at 1:2"))
+        );
+    }
+
+    #[test]
+    fn sources() {
+        assert!(expand_str(r#"pub fn f = "+[-]""#).is_ok());
+        let error = expand_str(r#"fn f = concat!("+")"#).unwrap_err();
+        assert!(error.starts_with("expected a string literal"));
+        let error = expand_str(r#"fn f = include!("no/such/file.bf");"#).unwrap_err();
+        assert!(error.starts_with("failed to read"));
+    }
+}
@@ -0,0 +1,36 @@
+use bf_macros::bf;
+
+bf! {
+    /// Echoes its input, clearing the cell so that it stops at the end.
+    fn cat = ",[.[-],]";
+}
+
+bf!(fn hello_world = include!("../programs/hello_world.bf"));
+
+bf!(fn multiply = "+++[->++++<]>>+<");
+
+bf!(fn off_the_tape = "<+");
+
+#[test]
+fn io() {
+    let mut output = vec![];
+    cat(&mut [0; 1], &mut b"abc".as_slice(), &mut output).unwrap();
+    assert_eq!(output, b"abc");
+
+    let mut output = vec![];
+    hello_world(&mut [0; 100], &mut std::io::empty(), &mut output).unwrap();
+    assert_eq!(output, b"Hello World!\n");
+}
+
+#[test]
+fn tape() {
+    let mut tape = [0; 3];
+    multiply(&mut tape, &mut std::io::empty(), &mut std::io::sink()).unwrap();
+    assert_eq!(tape, [0, 12, 1]);
+}
+
+#[test]
+#[should_panic]
+fn out_of_bounds() {
+    let _ = off_the_tape(&mut [0; 1], &mut std::io::empty(), &mut std::io::sink());
+}
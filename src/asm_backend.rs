@@ -0,0 +1,274 @@
+use std::fmt::Write;
+
+use crate::{
+    bytecode_bf::{ByteCode, ByteCodeProgram, Change},
+    io::EofPolicy,
+    jit_utils::JitOptions,
+    parser::{Parser, SourceLoc},
+    MEMORY_SIZE,
+};
+
+/// Operand order and spelling of the emitted assembly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AsmSyntax {
+    #[default]
+    Att,
+    /// `.intel_syntax noprefix`
+    Intel,
+}
+
+/// What only the assembly backend can be configured with.
+#[derive(Debug, Clone, Default)]
+pub struct AsmOptions {
+    pub syntax: AsmSyntax,
+}
+
+/// Turns bytecode into GNU assembler source with the instructions
+/// `BytecodeJit` generates, to read, tweak and build with `as` or `cc`.
+pub struct AsmEmitter {}
+
+impl AsmEmitter {
+    /// Parses `src` into the bytecode `BytecodeJit` runs, for `emit`.
+    pub fn parse_and_emit(src: String, options: &JitOptions, asm_options: &AsmOptions) -> String {
+        let prog = Parser::parse_to_bytecode(src.clone());
+        Self::emit(&prog, &src, options, asm_options)
+    }
+
+    /// x86-64 assembly for `prog`, parsed from `src`, in `asm_options.syntax`.
+    /// It defines `main`, so `cc` links it into a program that uses a
+    /// `MEMORY_SIZE` tape in `.bss` and raw `read`/`write` syscalls, with `,`
+    /// following `options.eof_policy`. Like the JIT it doesn't check that the
+    /// pointer stays on the tape.
+    ///
+    /// Each loop gets a `loop_<line>_<col>` label after its `[`, and every
+    /// source line with instructions is copied into a comment above them.
+    pub fn emit(
+        prog: &ByteCodeProgram,
+        src: &str,
+        options: &JitOptions,
+        asm_options: &AsmOptions,
+    ) -> String {
+        let lines: Vec<&str> = src.lines().collect();
+        let mut asm = Assembly {
+            out: String::new(),
+            syntax: asm_options.syntax,
+        };
+        writeln!(
+            asm.out,
+            "# Generated by bf_interpreter from {}.",
+            options.source_name()
+        )
+        .unwrap();
+        if asm.syntax == AsmSyntax::Intel {
+            asm.out += "    .intel_syntax noprefix\n";
+        }
+        asm.out += "    .text\n    .globl main\n    .type main, @function\nmain:\n";
+        // r13 is callee-saved, and keeps the data pointer like in the JIT
+        asm.instr("push %r13", "push r13");
+        asm.instr("lea tape(%rip), %r13", "lea r13, [rip + tape]");
+
+        let mut open_loops: Vec<SourceLoc> = vec![];
+        let mut last_line = 0;
+        for (instr, span) in prog.instructions.iter().zip(&prog.spans) {
+            if *instr == ByteCode::Nop {
+                continue;
+            }
+            let loc = span.start;
+            if loc.line != last_line {
+                let text = lines.get(loc.line - 1).copied().unwrap_or_default();
+                writeln!(asm.out, "\n# {}: {}", loc.line, text.trim_end()).unwrap();
+                last_line = loc.line;
+            }
+            writeln!(
+                asm.out,
+                "    # {:?} at {}:{}-{}:{}",
+                instr, span.start.line, span.start.col, span.end.line, span.end.col
+            )
+            .unwrap();
+            match *instr {
+                ByteCode::Nop => {}
+                ByteCode::DataPointerIncr(n) => {
+                    asm.instr(&format!("add ${}, %r13", n), &format!("add r13, {}", n))
+                }
+                ByteCode::DataPointerDecr(n) => {
+                    asm.instr(&format!("sub ${}, %r13", n), &format!("sub r13, {}", n))
+                }
+                ByteCode::DataIncr(n) => asm.instr(
+                    &format!("addb ${}, (%r13)", n as u8),
+                    &format!("add byte ptr [r13], {}", n as u8),
+                ),
+                ByteCode::DataDecr(n) => asm.instr(
+                    &format!("subb ${}, (%r13)", n as u8),
+                    &format!("sub byte ptr [r13], {}", n as u8),
+                ),
+                ByteCode::Write => asm.syscall(1, 1),
+                ByteCode::Read => {
+                    asm.syscall(0, 0);
+                    let on_eof = match options.eof_policy {
+                        EofPolicy::Zero => Some(0),
+                        EofPolicy::AllOnes => Some(255),
+                        EofPolicy::Unchanged => None,
+                    };
+                    // read returns 0 at the end of input, and leaves the cell
+                    if let Some(value) = on_eof {
+                        asm.instr("test %rax, %rax", "test rax, rax");
+                        asm.instr("jg 1f", "jg 1f");
+                        asm.instr(
+                            &format!("movb ${}, (%r13)", value),
+                            &format!("mov byte ptr [r13], {}", value),
+                        );
+                        asm.out += "1:\n";
+                    }
+                }
+                ByteCode::JZ => {
+                    asm.compare_cell();
+                    asm.instr(
+                        &format!("je {}_end", loop_label(loc)),
+                        &format!("je {}_end", loop_label(loc)),
+                    );
+                    writeln!(asm.out, "{}:", loop_label(loc)).unwrap();
+                    open_loops.push(loc);
+                }
+                ByteCode::JNZ => {
+                    let open = open_loops
+                        .pop()
+                        .unwrap_or_else(|| panic!("Unmatched ] at {}:{}", loc.line, loc.col));
+                    asm.compare_cell();
+                    asm.instr(
+                        &format!("jne {}", loop_label(open)),
+                        &format!("jne {}", loop_label(open)),
+                    );
+                    writeln!(asm.out, "{}_end:", loop_label(open)).unwrap();
+                }
+                ByteCode::SETZERO => asm.instr("movb $0, (%r13)", "mov byte ptr [r13], 0"),
+                ByteCode::MoveInStepUntilZero(change) => {
+                    let (att, intel) = match change {
+                        Change::Incr(n) => (format!("add ${}, %r13", n), format!("add r13, {}", n)),
+                        Change::Decr(n) => (format!("sub ${}, %r13", n), format!("sub r13, {}", n)),
+                    };
+                    asm.out += "1:\n";
+                    asm.compare_cell();
+                    asm.instr("je 2f", "je 2f");
+                    asm.instr(&att, &intel);
+                    asm.instr("jmp 1b", "jmp 1b");
+                    asm.out += "2:\n";
+                }
+                ByteCode::MultiplyAdd(offset, factor) => {
+                    asm.instr("movzbl (%r13), %eax", "movzx eax, byte ptr [r13]");
+                    asm.instr("test %eax, %eax", "test eax, eax");
+                    asm.instr("je 1f", "je 1f");
+                    asm.instr(
+                        &format!("imul ${}, %eax, %eax", factor),
+                        &format!("imul eax, eax, {}", factor),
+                    );
+                    asm.instr(
+                        &format!("addb %al, {}(%r13)", offset),
+                        &format!(
+                            "add byte ptr [r13 {} {}], al",
+                            if offset < 0 { '-' } else { '+' },
+                            offset.unsigned_abs()
+                        ),
+                    );
+                    asm.out += "1:\n";
+                }
+            }
+        }
+        if let Some(open) = open_loops.pop() {
+            panic!("Unmatched [ at {}:{}", open.line, open.col);
+        }
+
+        asm.out += "\n";
+        asm.instr("pop %r13", "pop r13");
+        asm.instr("xor %eax, %eax", "xor eax, eax");
+        asm.instr("ret", "ret");
+        asm.out += "    .size main, .-main\n\n";
+        writeln!(asm.out, "    .lcomm tape, {}", MEMORY_SIZE).unwrap();
+        asm.out += "    .section .note.GNU-stack,\"\",@progbits\n";
+        asm.out
+    }
+}
+
+fn loop_label(open: SourceLoc) -> String {
+    format!("loop_{}_{}", open.line, open.col)
+}
+
+struct Assembly {
+    out: String,
+    syntax: AsmSyntax,
+}
+
+impl Assembly {
+    /// One instruction, written `att` or `intel` depending on the syntax.
+    fn instr(&mut self, att: &str, intel: &str) {
+        let text = match self.syntax {
+            AsmSyntax::Att => att,
+            AsmSyntax::Intel => intel,
+        };
+        writeln!(self.out, "    {}", text).unwrap();
+    }
+
+    fn compare_cell(&mut self) {
+        self.instr("cmpb $0, (%r13)", "cmp byte ptr [r13], 0");
+    }
+
+    /// `read` or `write` of the cell with file descriptor `fd`.
+    fn syscall(&mut self, number: u32, fd: u32) {
+        self.instr(
+            &format!("mov ${}, %eax", number),
+            &format!("mov eax, {}", number),
+        );
+        self.instr(&format!("mov ${}, %edi", fd), &format!("mov edi, {}", fd));
+        self.instr("mov %r13, %rsi", "mov rsi, r13");
+        self.instr("mov $1, %edx", "mov edx, 1");
+        self.instr("syscall", "syscall");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AsmEmitter, AsmOptions};
+    use crate::jit_utils::JitOptions;
+
+    #[test]
+    fn readable_output() {
+        let src = "+\n[-\n]";
+        let asm = AsmEmitter::parse_and_emit(
+            src.to_owned(),
+            &JitOptions::default(),
+            &AsmOptions::default(),
+        );
+        let main = asm.split("main:\n").nth(1).unwrap();
+        assert_eq!(
+            main,
+            "    push %r13
+    lea tape(%rip), %r13
+
+# 1: +
+    # DataIncr(1) at 1:1-1:1
+    addb $1, (%r13)
+
+# 2: [-
+    # JZ at 2:1-2:1
+    cmpb $0, (%r13)
+    je loop_2_1_end
+loop_2_1:
+    # DataDecr(1) at 2:2-2:2
+    subb $1, (%r13)
+
+# 3: ]
+    # JNZ at 3:1-3:1
+    cmpb $0, (%r13)
+    jne loop_2_1
+loop_2_1_end:
+
+    pop %r13
+    xor %eax, %eax
+    ret
+    .size main, .-main
+
+    .lcomm tape, 30000
+    .section .note.GNU-stack,\"\",@progbits
+"
+        );
+    }
+}
@@ -0,0 +1,388 @@
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{Empty, Sink};
+use std::mem::transmute_copy;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "cranelift")]
+use crate::cranelift_jit::CraneliftJit;
+#[cfg(any(feature = "llvm", feature = "cranelift"))]
+use crate::jit_utils::{JitOptions, JitResult, StageTimings};
+#[cfg(feature = "llvm")]
+use crate::llvm_jit::LlvmJit;
+use crate::{
+    io::{EofPolicy, Io},
+    jit_utils::{JitProgram, STATUS_OK},
+    optbytecode_jit::BytecodeJit,
+    parser::Parser,
+    simple_jit::SimpleJit,
+    threaded::ThreadedCode,
+    tiered::TieredJit,
+    MEMORY_SIZE,
+};
+
+/// Programs run by `measure` get no input, and their output is thrown away.
+type BenchIo = Io<Empty, Sink>;
+
+/// What `,` stores in programs run by `measure`, on every backend.
+const EOF_POLICY: EofPolicy = EofPolicy::Unchanged;
+
+/// Every backend that runs programs in this process, for `measure`.
+///
+/// `bytecode-jit-io` is the code `BytecodeJit` compiles to call `Io`, as the
+/// tiered engine runs it, not the syscall code of `--backend bytecode-jit`.
+pub fn backends() -> Vec<&'static str> {
+    let mut backends = vec![
+        "interpreter",
+        "bytecode",
+        "threaded",
+        "simple-jit",
+        "bytecode-jit-io",
+        "tiered",
+    ];
+    if cfg!(feature = "cranelift") {
+        backends.push("cranelift");
+    }
+    if cfg!(feature = "llvm") {
+        backends.push("llvm");
+    }
+    backends
+}
+
+/// The median time of each stage over the runs of a program on a backend.
+/// Stages a backend doesn't have take no time: the interpreters compile
+/// nothing, and the tiered engine compiles while it executes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub program: String,
+    pub backend: &'static str,
+    pub runs: usize,
+    pub parse: Duration,
+    pub optimize: Duration,
+    pub compile: Duration,
+    pub execute: Duration,
+    /// Bytes of machine code, for the backends that generate it up front.
+    pub code_size: Option<usize>,
+}
+
+#[derive(Default)]
+struct Run {
+    parse: Duration,
+    optimize: Duration,
+    compile: Duration,
+    execute: Duration,
+    code_size: Option<usize>,
+}
+
+/// Runs `src`, called `program` in the results, `runs` times on `backend`,
+/// one of `backends()`.
+pub fn measure(program: &str, src: &str, backend: &'static str, runs: usize) -> Measurement {
+    assert!(runs > 0, "Need at least one run");
+    let runs: Vec<Run> = (0..runs).map(|_| run_once(src, backend)).collect();
+    let median = |stage: fn(&Run) -> Duration| {
+        let mut times: Vec<Duration> = runs.iter().map(stage).collect();
+        times.sort();
+        times[times.len() / 2]
+    };
+    Measurement {
+        program: program.to_owned(),
+        backend,
+        runs: runs.len(),
+        parse: median(|run| run.parse),
+        optimize: median(|run| run.optimize),
+        compile: median(|run| run.compile),
+        execute: median(|run| run.execute),
+        code_size: runs[0].code_size,
+    }
+}
+
+fn timed<T>(time: &mut Duration, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    *time = start.elapsed();
+    result
+}
+
+fn run_once(src: &str, backend: &str) -> Run {
+    let mut run = Run::default();
+    let mut tape = vec![0; MEMORY_SIZE];
+    let mut io: BenchIo = Io::new(std::io::empty(), std::io::sink(), EOF_POLICY);
+    let src = src.to_owned();
+    match backend {
+        "interpreter" => {
+            let prog = timed(&mut run.parse, || Parser::parse(src));
+            timed(&mut run.execute, || {
+                prog.eval_with_io(
+                    &mut (),
+                    &mut std::io::empty(),
+                    &mut std::io::sink(),
+                    EOF_POLICY,
+                )
+            });
+        }
+        "bytecode" => {
+            let mut prog = timed(&mut run.parse, || Parser::parse_to_bytecode(src));
+            timed(&mut run.optimize, || prog.opt_pass_1());
+            timed(&mut run.execute, || {
+                prog.eval_with_io(
+                    &mut (),
+                    &mut std::io::empty(),
+                    &mut std::io::sink(),
+                    EOF_POLICY,
+                )
+            });
+        }
+        "threaded" => {
+            let mut prog = timed(&mut run.parse, || Parser::parse_to_bytecode(src));
+            timed(&mut run.optimize, || {
+                prog.opt_pass_1();
+                prog.opt_pass_2();
+            });
+            let code = timed(&mut run.compile, || ThreadedCode::new(&prog));
+            timed(&mut run.execute, || code.run(&mut tape, &mut io));
+        }
+        "simple-jit" => {
+            let prog = timed(&mut run.parse, || Parser::parse(src));
+            // reads get the end of input, and writes go nowhere
+            let null = File::options()
+                .read(true)
+                .write(true)
+                .open("/dev/null")
+                .expect("Failed to open /dev/null");
+            let fds = (null.as_raw_fd(), null.as_raw_fd());
+            let program = timed(&mut run.compile, || {
+                JitProgram::new(SimpleJit::compile(&prog, tape.as_mut_ptr(), fds))
+            });
+            run.code_size = Some(program.program_size());
+            timed(&mut run.execute, || unsafe {
+                let jit_fn: unsafe extern "C" fn() = transmute_copy(&program.program_memory());
+                jit_fn();
+            });
+        }
+        "bytecode-jit-io" => {
+            // unoptimized, like `BytecodeJit::parse_and_run`
+            let prog = timed(&mut run.parse, || Parser::parse_to_bytecode(src));
+            let code = timed(&mut run.compile, || {
+                BytecodeJit::compile_program::<Empty, Sink>(&prog)
+            });
+            run.code_size = Some(code.size());
+            let result = timed(&mut run.execute, || unsafe {
+                code.run(&mut tape, 0, &mut io as *mut BenchIo as *mut u8)
+            });
+            assert_eq!(
+                result.status, STATUS_OK,
+                "The program went off the tape on {}",
+                backend
+            );
+        }
+        "tiered" => {
+            let mut prog = timed(&mut run.parse, || Parser::parse_to_bytecode(src));
+            timed(&mut run.optimize, || {
+                prog.opt_pass_1();
+                prog.opt_pass_2();
+            });
+            timed(&mut run.execute, || {
+                TieredJit::default().run(&prog, &mut tape, &mut io)
+            });
+        }
+        #[cfg(feature = "cranelift")]
+        "cranelift" => {
+            let mut prog = timed(&mut run.parse, || Parser::parse_to_bytecode(src));
+            timed(&mut run.optimize, || {
+                prog.opt_pass_1();
+                prog.opt_pass_2();
+            });
+            let mut setup = Duration::ZERO;
+            let jit = timed(&mut setup, CraneliftJit::new);
+            let (result, timings) = jit.execute_timed(&prog, &mut tape, &mut io, &timed_options());
+            add_stages(&mut run, &timings, result, "cranelift");
+            run.compile += setup;
+        }
+        #[cfg(feature = "llvm")]
+        "llvm" => {
+            let mut prog = timed(&mut run.parse, || Parser::parse_to_bytecode(src));
+            timed(&mut run.optimize, || {
+                prog.opt_pass_1();
+                prog.opt_pass_2();
+            });
+            let mut setup = Duration::ZERO;
+            let jit = timed(&mut setup, LlvmJit::for_host);
+            let (result, timings) = jit.execute_timed(&prog, &mut tape, &mut io, &timed_options());
+            add_stages(&mut run, &timings, result, "llvm");
+            run.compile += setup;
+        }
+        _ => panic!("Unknown backend {}", backend),
+    }
+    run
+}
+
+/// Options that make the JITs measure their stages and code size.
+#[cfg(any(feature = "llvm", feature = "cranelift"))]
+fn timed_options() -> JitOptions {
+    JitOptions {
+        time_stages: true,
+        ..Default::default()
+    }
+}
+
+/// Adds the stages of a JIT to `run`: `optimize` and `execute` where they
+/// belong, and everything else to compilation.
+#[cfg(any(feature = "llvm", feature = "cranelift"))]
+fn add_stages(run: &mut Run, timings: &StageTimings, result: JitResult, backend: &str) {
+    assert_eq!(
+        result.status, STATUS_OK,
+        "The program went off the tape on {}",
+        backend
+    );
+    for (stage, time) in timings.stages() {
+        match *stage {
+            "optimize" => run.optimize += *time,
+            "execute" => run.execute += *time,
+            _ => run.compile += *time,
+        }
+    }
+    run.code_size = timings.code_size();
+}
+
+fn millis(time: Duration) -> f64 {
+    time.as_secs_f64() * 1000.0
+}
+
+/// The measurements as a table, times in milliseconds.
+pub fn table(measurements: &[Measurement]) -> String {
+    let width = measurements
+        .iter()
+        .map(|m| m.program.len())
+        .chain(["program".len()])
+        .max()
+        .unwrap();
+    let mut out = format!(
+        "{:<width$}  {:<12} {:>10} {:>10} {:>10} {:>10} {:>10}\n",
+        "program", "backend", "parse", "optimize", "compile", "execute", "code size",
+    );
+    for m in measurements {
+        let code_size = m
+            .code_size
+            .map_or(String::from("-"), |size| size.to_string());
+        writeln!(
+            out,
+            "{:<width$}  {:<12} {:>10.3} {:>10.3} {:>10.3} {:>10.3} {:>10}",
+            m.program,
+            m.backend,
+            millis(m.parse),
+            millis(m.optimize),
+            millis(m.compile),
+            millis(m.execute),
+            code_size,
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// The measurements as a JSON array, times in milliseconds.
+pub fn json(measurements: &[Measurement]) -> String {
+    let mut out = String::from("[");
+    for (i, m) in measurements.iter().enumerate() {
+        if i > 0 {
+            out += ",";
+        }
+        let code_size = m
+            .code_size
+            .map_or(String::from("null"), |size| size.to_string());
+        write!(
+            out,
+            "\n  {{\"program\": {}, \"backend\": \"{}\", \"runs\": {}, \"parse_ms\": {:.6}, \
+             \"optimize_ms\": {:.6}, \"compile_ms\": {:.6}, \"execute_ms\": {:.6}, \
+             \"code_size\": {}}}",
+            json_string(&m.program),
+            m.backend,
+            m.runs,
+            millis(m.parse),
+            millis(m.optimize),
+            millis(m.compile),
+            millis(m.execute),
+            code_size,
+        )
+        .unwrap();
+    }
+    out += "\n]\n";
+    out
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{json, json_string, measure, table, Measurement};
+
+    #[test]
+    fn measurements() {
+        let code = include_str!("../programs/hello_world.bf");
+        for backend in [
+            "interpreter",
+            "threaded",
+            "simple-jit",
+            "bytecode-jit-io",
+            "tiered",
+        ] {
+            let m = measure("hello_world.bf", code, backend, 3);
+            assert_eq!(m.runs, 3);
+            assert!(m.execute > Duration::ZERO, "{}", backend);
+            let jit = backend.contains("jit");
+            assert_eq!(m.code_size.is_some(), jit, "{}", backend);
+            assert_eq!(m.compile > Duration::ZERO, jit || backend == "threaded");
+        }
+    }
+
+    #[test]
+    fn output_formats() {
+        let m = Measurement {
+            program: String::from("a \"b\".bf"),
+            backend: "llvm",
+            runs: 5,
+            parse: Duration::from_micros(1500),
+            optimize: Duration::from_millis(2),
+            compile: Duration::from_millis(30),
+            execute: Duration::from_millis(400),
+            code_size: Some(1234),
+        };
+        let interpreted = Measurement {
+            backend: "interpreter",
+            code_size: None,
+            ..m.clone()
+        };
+        assert_eq!(
+            table(&[m.clone(), interpreted.clone()]),
+            "\
+program   backend           parse   optimize    compile    execute  code size
+a \"b\".bf  llvm              1.500      2.000     30.000    400.000       1234
+a \"b\".bf  interpreter       1.500      2.000     30.000    400.000          -
+"
+        );
+        assert_eq!(
+            json(&[m, interpreted]),
+            "[
+  {\"program\": \"a \\\"b\\\".bf\", \"backend\": \"llvm\", \"runs\": 5, \"parse_ms\": 1.500000, \"optimize_ms\": 2.000000, \"compile_ms\": 30.000000, \"execute_ms\": 400.000000, \"code_size\": 1234},
+  {\"program\": \"a \\\"b\\\".bf\", \"backend\": \"interpreter\", \"runs\": 5, \"parse_ms\": 1.500000, \"optimize_ms\": 2.000000, \"compile_ms\": 30.000000, \"execute_ms\": 400.000000, \"code_size\": null}
+]
+"
+        );
+        assert_eq!(json_string("\\\n"), "\"\\\\\\u000a\"");
+    }
+}
@@ -1,9 +1,12 @@
-use std::io::stdin;
+use std::io::{stdin, stdout, BufRead, Write};
 
-use crate::MEMORY_SIZE;
+use crate::{
+    coverage::Coverage, io::EofPolicy, observer::ExecutionObserver, parser::SourceLoc, MEMORY_SIZE,
+};
 
 pub struct Program {
     pub instructions: Vec<char>,
+    pub locations: Vec<SourceLoc>,
 }
 impl Program {
     pub fn compute_jumptable(&self) -> Vec<usize> {
@@ -37,12 +40,42 @@ impl Program {
 
     /// https://eli.thegreenplace.net/2017/adventures-in-jit-compilation-part-1-an-interpreter/
     pub fn eval(&self) {
+        self.eval_with_observer(&mut ());
+    }
+
+    /// Runs the program and records per-instruction and per-bracket counts.
+    pub fn eval_with_coverage(&self) -> Coverage {
+        let mut coverage = Coverage::for_program(self);
+        self.eval_with_observer(&mut coverage);
+        coverage
+    }
+
+    pub fn eval_with_observer<O: ExecutionObserver>(&self, observer: &mut O) {
+        self.eval_with_io(
+            observer,
+            &mut stdin().lock(),
+            &mut stdout().lock(),
+            EofPolicy::default(),
+        );
+    }
+
+    /// Runs the program reading a line of `input` for every `,`, which
+    /// stores its first byte, or what `eof_policy` says at the end of
+    /// input, and writing to `output`.
+    pub fn eval_with_io<O: ExecutionObserver, R: BufRead, W: Write>(
+        &self,
+        observer: &mut O,
+        input: &mut R,
+        output: &mut W,
+        eof_policy: EofPolicy,
+    ) {
         let mut memory = vec![0 as u8; MEMORY_SIZE];
         let mut data_counter = 0;
         let mut pc = 0;
         let jumptable = self.compute_jumptable();
         while pc < self.instructions.len() {
             let instr = self.instructions[pc];
+            observer.instruction(pc);
             match instr {
                 '>' => {
                     data_counter += 1;
@@ -51,28 +84,36 @@ impl Program {
                     data_counter -= 1.min(data_counter);
                 }
                 '+' => {
-                    memory[data_counter] += 1;
+                    memory[data_counter] = memory[data_counter].wrapping_add(1);
                 }
                 '-' => {
-                    memory[data_counter] -= 1;
+                    memory[data_counter] = memory[data_counter].wrapping_sub(1);
                 }
                 '.' => {
-                    print!("{}", memory[data_counter] as char);
+                    // the byte itself, not its char's UTF-8 encoding
+                    output
+                        .write_all(&[memory[data_counter]])
+                        .expect("Failed to write output");
                 }
                 ',' => {
                     let mut inp = String::new();
-                    stdin()
-                        .read_line(&mut inp)
-                        .expect("Failed to read from stdin");
-                    memory[data_counter] = inp.as_bytes()[0];
+                    memory[data_counter] =
+                        match input.read_line(&mut inp).expect("Failed to read input") {
+                            0 => eof_policy.on_eof(memory[data_counter]),
+                            _ => inp.as_bytes()[0],
+                        };
                 }
                 '[' => {
-                    if memory[data_counter] == 0 {
+                    let taken = memory[data_counter] == 0;
+                    observer.branch(pc, taken);
+                    if taken {
                         pc = jumptable[pc];
                     }
                 }
                 ']' => {
-                    if memory[data_counter] != 0 {
+                    let taken = memory[data_counter] != 0;
+                    observer.branch(pc, taken);
+                    if taken {
                         pc = jumptable[pc];
                     }
                 }
@@ -80,7 +121,7 @@ impl Program {
             }
             pc += 1;
         }
-        println!("");
+        writeln!(output).expect("Failed to write output");
     }
 }
 
@@ -0,0 +1,225 @@
+//! Compiles `.bf` files into a crate from its build script, with the LLVM
+//! backend.
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     bf_interpreter::build::compile("bf_programs", &["src/hello.bf"], &Default::default());
+//! }
+//!
+//! // src/lib.rs
+//! include!(concat!(env!("OUT_DIR"), "/bf_programs.rs"));
+//!
+//! pub fn greeting() -> Vec<u8> {
+//!     bf_programs::hello(b"")
+//! }
+//! ```
+use std::path::{Path, PathBuf};
+
+use crate::{
+    jit_utils::{JitOptions, STATUS_OK},
+    llvm_jit::{LibraryFunction, LlvmJit},
+    MEMORY_SIZE,
+};
+
+/// Compiles `files` into the static library `lib<name>.a` in `$OUT_DIR`,
+/// tells cargo to link it and to rerun the build script when they change,
+/// and writes `$OUT_DIR/<name>.rs` with a module `<name>` that has a wrapper
+/// per file, named after it.
+pub fn compile<P: AsRef<Path>>(name: &str, files: &[P], options: &JitOptions) {
+    let out_dir =
+        PathBuf::from(std::env::var_os("OUT_DIR").expect("OUT_DIR is only set for build scripts"));
+    for file in files {
+        println!("cargo:rerun-if-changed={}", file.as_ref().display());
+    }
+    compile_to(name, files, options, &out_dir);
+    println!("cargo:rustc-link-search=native={}", out_dir.display());
+    println!("cargo:rustc-link-lib=static={}", name);
+}
+
+/// What `compile` does, without cargo: writes `lib<name>.a` and `<name>.rs`
+/// to `out_dir`.
+pub fn compile_to<P: AsRef<Path>>(name: &str, files: &[P], options: &JitOptions, out_dir: &Path) {
+    if !is_identifier(name) {
+        panic!("{:?} can't be used as a module name", name);
+    }
+    let mut wrappers = vec![];
+    let mut functions = vec![];
+    for file in files {
+        let path = file.as_ref().to_string_lossy().into_owned();
+        let src = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e));
+        let mut function = LibraryFunction::parse(&path, src);
+        let wrapper = if function.name.starts_with(|c: char| c.is_ascii_digit()) {
+            format!("_{}", function.name)
+        } else {
+            function.name.clone()
+        };
+        // Exported from the library of every crate that does this, so each
+        // gets its own names.
+        function.name = format!("{}_{}", name, function.name);
+        wrappers.push(wrapper);
+        functions.push(function);
+    }
+
+    let library = out_dir.join(format!("lib{}.a", name));
+    LlvmJit::for_host().build_static_library(&functions, &library, options);
+
+    let source = out_dir.join(format!("{}.rs", name));
+    std::fs::write(&source, rust_wrappers(name, &wrappers, &functions))
+        .unwrap_or_else(|e| panic!("Failed to write {}: {}", source.display(), e));
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Source of the module `name`, with a function called `wrappers[i]` for
+/// each of `functions`. It only depends on `std`, the crate that includes it
+/// has `bf_interpreter` as a build dependency at most.
+fn rust_wrappers(name: &str, wrappers: &[String], functions: &[LibraryFunction]) -> String {
+    let mut source = format!(
+        r#"// Generated by bf_interpreter::build, do not edit.
+pub mod {name} {{
+    /// Cells each program gets, all zero at the start.
+    pub const TAPE_LEN: usize = {tape_len};
+
+    /// `bf_io` of the C ABI.
+    #[repr(C)]
+    struct Io {{
+        ctx: *mut u8,
+        read: unsafe extern "C" fn(*mut u8) -> i32,
+        write: unsafe extern "C" fn(*mut u8, u8),
+    }}
+
+    struct Buffers<'a> {{
+        input: &'a [u8],
+        output: Vec<u8>,
+    }}
+
+    unsafe extern "C" fn read_input(ctx: *mut u8) -> i32 {{
+        let buffers = unsafe {{ &mut *(ctx as *mut Buffers) }};
+        match buffers.input.split_first() {{
+            Some((&c, rest)) => {{
+                buffers.input = rest;
+                c as i32
+            }}
+            None => -1,
+        }}
+    }}
+
+    unsafe extern "C" fn write_output(ctx: *mut u8, c: u8) {{
+        let buffers = unsafe {{ &mut *(ctx as *mut Buffers) }};
+        buffers.output.push(c);
+    }}
+
+    fn run_program(
+        program: unsafe extern "C" fn(*mut u8, usize, *mut Io) -> i32,
+        source: &str,
+        input: &[u8],
+    ) -> Vec<u8> {{
+        let mut tape = vec![0u8; TAPE_LEN];
+        let mut buffers = Buffers {{
+            input,
+            output: Vec::new(),
+        }};
+        let mut io = Io {{
+            ctx: &mut buffers as *mut Buffers as *mut u8,
+            read: read_input,
+            write: write_output,
+        }};
+        let status = unsafe {{ program(tape.as_mut_ptr(), tape.len(), &mut io) }};
+        if status != {ok} {{
+            panic!("{{}} moved the data pointer off the tape", source);
+        }}
+        buffers.output
+    }}
+
+    unsafe extern "C" {{
+"#,
+        name = name,
+        tape_len = MEMORY_SIZE,
+        ok = STATUS_OK,
+    );
+    for function in functions {
+        source += &format!(
+            "        fn bf_{}(tape: *mut u8, len: usize, io: *mut Io) -> i32;\n",
+            function.name
+        );
+    }
+    source += "    }\n";
+    for (wrapper, function) in wrappers.iter().zip(functions) {
+        source += &format!(
+            r#"
+    /// Runs `{source}` on `input` and returns its output.
+    ///
+    /// Panics if the program moves the data pointer off the tape.
+    pub fn {wrapper}(input: &[u8]) -> Vec<u8> {{
+        run_program(bf_{symbol}, {source:?}, input)
+    }}
+"#,
+            source = function.source_name,
+            wrapper = wrapper,
+            symbol = function.name,
+        );
+    }
+    source += "}\n";
+    source
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use crate::jit_utils::JitOptions;
+
+    #[test]
+    fn compile_to() {
+        let dir = std::env::temp_dir().join(format!("bf-build-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cat = dir.join("1-cat.bf");
+        std::fs::write(&cat, ",[.,]").unwrap();
+        let files = [
+            std::path::PathBuf::from("programs/hello_world.bf"),
+            cat.clone(),
+        ];
+        let options = JitOptions {
+            eof_policy: crate::io::EofPolicy::Zero,
+            ..Default::default()
+        };
+        super::compile_to("bf_programs", &files, &options, &dir);
+        assert!(dir.join("libbf_programs.a").exists());
+        let source = std::fs::read_to_string(dir.join("bf_programs.rs")).unwrap();
+        assert!(source.contains("pub fn hello_world(input: &[u8]) -> Vec<u8>"));
+        assert!(source.contains("pub fn _1_cat(input: &[u8]) -> Vec<u8>"));
+        assert!(source.contains("fn bf_bf_programs_1_cat("));
+
+        // what a crate with the build script would compile
+        let main = dir.join("main.rs");
+        std::fs::write(
+            &main,
+            r#"include!("bf_programs.rs");
+
+fn main() {
+    assert_eq!(bf_programs::hello_world(b""), b"Hello World!\n");
+    assert_eq!(bf_programs::_1_cat(b"abc"), b"abc");
+}
+"#,
+        )
+        .unwrap();
+        let exe = dir.join("main");
+        let status = Command::new("rustc")
+            .args(["--edition", "2021", "-L"])
+            .arg(&dir)
+            .args(["-l", "static=bf_programs", "-o"])
+            .arg(&exe)
+            .arg(&main)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let status = Command::new(&exe).status().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(status.success());
+    }
+}
@@ -1,6 +1,16 @@
-use std::{io::stdin, mem::replace};
+use std::{
+    io::{stdin, stdout, BufRead, Write},
+    mem::replace,
+};
 
-use crate::MEMORY_SIZE;
+use crate::{
+    compact::{CompactProgram, Instructions, Op},
+    coverage::Coverage,
+    io::EofPolicy,
+    observer::ExecutionObserver,
+    parser::Span,
+    MEMORY_SIZE,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub enum Change {
@@ -21,40 +31,30 @@ pub enum ByteCode {
     JNZ,                         // Jump not Zero
     SETZERO,                     // Set Current Cell to Zero , [+] or [-]
     MoveInStepUntilZero(Change), // Moves the data_counter in certain increments until it encounters a cell which is zero [>>>>] or [<<<<] instructions
+    /// Adds the current cell times a factor to the cell at an offset, if
+    /// the current cell isn't zero: the loop it replaces never touches that
+    /// cell then, which matters when it is off the tape.
+    MultiplyAdd(isize, u8),
 }
 
 pub struct ByteCodeProgram {
     pub instructions: Vec<ByteCode>,
+    pub spans: Vec<Span>,
 }
 
 impl ByteCodeProgram {
-    fn compute_jumptable(&self) -> Vec<usize> {
-        let mut pc = 0;
-        let prog_size = self.instructions.len();
-        let mut jumptable = vec![0; prog_size];
-        while pc < prog_size {
-            let instr = self.instructions[pc];
-            if instr == ByteCode::JZ {
-                let mut nesting = 1;
-                let mut seek = pc;
-                while nesting > 0 && (seek + 1) < prog_size {
-                    seek += 1;
-                    if self.instructions[seek] == ByteCode::JNZ {
-                        nesting -= 1;
-                    } else if self.instructions[seek] == ByteCode::JZ {
-                        nesting += 1;
-                    }
-                }
-                if nesting == 0 {
-                    jumptable[pc] = seek;
-                    jumptable[seek] = pc;
-                } else {
-                    panic!("unmatched '[' at pc= {}", pc);
-                }
+    /// Index of a bracket without a match, if there is one: the first `]`
+    /// that closes nothing, or else the first `[` left open.
+    pub fn unmatched_bracket(&self) -> Option<usize> {
+        let mut open = vec![];
+        for (pc, instr) in self.instructions.iter().enumerate() {
+            match instr {
+                ByteCode::JZ => open.push(pc),
+                ByteCode::JNZ if open.pop().is_none() => return Some(pc),
+                _ => {}
             }
-            pc += 1;
         }
-        jumptable
+        open.first().copied()
     }
 
     fn is_set_zero(instructions: &[ByteCode]) -> bool {
@@ -88,12 +88,55 @@ impl ByteCodeProgram {
         return None;
     }
 
+    /// Targets of a loop like `[->++>+++<<]`, which adds the current cell
+    /// times a factor to cells at fixed offsets and leaves it zero, together
+    /// with the index of its `JNZ`.
+    fn is_multiply_loop(instructions: &[ByteCode]) -> Option<(usize, Vec<(isize, u8)>)> {
+        if instructions.first() != Some(&ByteCode::JZ) {
+            return None;
+        }
+        let mut offset: isize = 0;
+        // net change per offset, in the order the cells are first touched
+        let mut changes: Vec<(isize, u8)> = vec![];
+        for (index, instr) in instructions.iter().enumerate().skip(1) {
+            match *instr {
+                ByteCode::DataPointerIncr(x) => offset += x as isize,
+                ByteCode::DataPointerDecr(x) => offset -= x as isize,
+                ByteCode::DataIncr(x) | ByteCode::DataDecr(x) => {
+                    let x = x as u8;
+                    let change = match instr {
+                        ByteCode::DataIncr(_) => x,
+                        _ => x.wrapping_neg(),
+                    };
+                    match changes.iter_mut().find(|(o, _)| *o == offset) {
+                        Some((_, total)) => *total = total.wrapping_add(change),
+                        None => changes.push((offset, change)),
+                    }
+                }
+                ByteCode::JNZ => {
+                    let counter = changes.iter().position(|(o, _)| *o == 0)?;
+                    // Any other step could take more than 256 iterations or never end.
+                    if offset != 0 || changes[counter].1 != u8::MAX {
+                        return None;
+                    }
+                    changes.remove(counter);
+                    changes.retain(|(_, factor)| *factor != 0);
+                    return Some((index, changes));
+                }
+                _ => return None,
+            }
+        }
+        None
+    }
+
     pub fn opt_pass_1(&mut self) {
         //
         let mut index = 0;
         let prog_size = self.instructions.len();
         let mut new_instructions = vec![];
+        let mut new_spans = vec![];
         while index < prog_size {
+            let start = index;
             new_instructions.push(match self.instructions[index] {
                 ByteCode::JZ => {
                     if Self::is_set_zero(&self.instructions[index..]) {
@@ -111,69 +154,169 @@ impl ByteCodeProgram {
                 }
                 instr => instr,
             });
+            new_spans.push(self.spans[start].merge(self.spans[index]));
             index += 1;
         }
         let _ = replace(&mut self.instructions, new_instructions);
+        let _ = replace(&mut self.spans, new_spans);
     }
+    /// Replaces multiply loops with a `MultiplyAdd` per target and a
+    /// `SETZERO`, all of which get the span of the whole loop. Expects the
+    /// output of `opt_pass_1`.
+    pub fn opt_pass_2(&mut self) {
+        let mut index = 0;
+        let mut new_instructions = vec![];
+        let mut new_spans = vec![];
+        while index < self.instructions.len() {
+            match Self::is_multiply_loop(&self.instructions[index..]) {
+                Some((end, targets)) => {
+                    let span = self.spans[index].merge(self.spans[index + end]);
+                    for (offset, factor) in targets {
+                        new_instructions.push(ByteCode::MultiplyAdd(offset, factor));
+                        new_spans.push(span);
+                    }
+                    new_instructions.push(ByteCode::SETZERO);
+                    new_spans.push(span);
+                    index += end + 1;
+                }
+                None => {
+                    new_instructions.push(self.instructions[index]);
+                    new_spans.push(self.spans[index]);
+                    index += 1;
+                }
+            }
+        }
+        self.instructions = new_instructions;
+        self.spans = new_spans;
+    }
+
     pub fn eval(&self) {
+        self.eval_with_observer(&mut ());
+    }
+
+    /// Runs the program and records per-instruction and per-bracket counts.
+    /// Loops folded by `opt_pass_1` only count as a single instruction.
+    pub fn eval_with_coverage(&self) -> Coverage {
+        let mut coverage = Coverage::for_bytecode(self);
+        self.eval_with_observer(&mut coverage);
+        coverage
+    }
+
+    pub fn eval_with_observer<O: ExecutionObserver>(&self, observer: &mut O) {
+        self.eval_with_io(
+            observer,
+            &mut stdin().lock(),
+            &mut stdout().lock(),
+            EofPolicy::default(),
+        );
+    }
+
+    /// Runs the program reading a line of `input` for every `,`, which
+    /// stores its first byte, or what `eof_policy` says at the end of
+    /// input, and writing to `output`.
+    pub fn eval_with_io<O: ExecutionObserver, R: BufRead, W: Write>(
+        &self,
+        observer: &mut O,
+        input: &mut R,
+        output: &mut W,
+        eof_policy: EofPolicy,
+    ) {
+        match CompactProgram::from_bytecode(self) {
+            Ok(code) => Self::run(&code, observer, input, output, eof_policy),
+            // too large for the compact encoding
+            Err(_) => Self::run(
+                Op::from_bytecode(self).as_slice(),
+                observer,
+                input,
+                output,
+                eof_policy,
+            ),
+        }
+    }
+
+    fn run<I, O, R, W>(
+        code: &I,
+        observer: &mut O,
+        input: &mut R,
+        output: &mut W,
+        eof_policy: EofPolicy,
+    ) where
+        I: Instructions + ?Sized,
+        O: ExecutionObserver,
+        R: BufRead,
+        W: Write,
+    {
         let mut memory = vec![0 as u8; MEMORY_SIZE];
         let mut data_counter = 0;
         let mut pc = 0;
-        let jumptable = self.compute_jumptable();
-        while pc < self.instructions.len() {
-            let instr = self.instructions[pc];
-            match instr {
-                ByteCode::DataPointerIncr(x) => {
+        while let Some(op) = code.get(pc) {
+            observer.instruction(pc);
+            match op {
+                Op::Nop => {}
+                Op::Right(x) => {
                     data_counter += x;
                 }
-                ByteCode::DataPointerDecr(x) => {
+                Op::Left(x) => {
                     data_counter -= x.min(data_counter);
                 }
-                ByteCode::DataIncr(x) => {
-                    memory[data_counter] = (memory[data_counter] as usize + x) as u8;
+                Op::Add(x) => {
+                    memory[data_counter] = memory[data_counter].wrapping_add(x as u8);
                 }
-                ByteCode::DataDecr(x) => {
-                    memory[data_counter] = (memory[data_counter] as usize
-                        - x.min(memory[data_counter] as usize))
-                        as u8;
+                Op::Sub(x) => {
+                    memory[data_counter] = memory[data_counter].wrapping_sub(x as u8);
                 }
-                ByteCode::Write => {
-                    print!("{}", memory[data_counter] as char);
+                Op::Write => {
+                    // the byte itself, not its char's UTF-8 encoding
+                    output
+                        .write_all(&[memory[data_counter]])
+                        .expect("Failed to write output");
                 }
-                ByteCode::Read => {
+                Op::Read => {
                     let mut inp = String::new();
-                    stdin()
-                        .read_line(&mut inp)
-                        .expect("Failed to read from stdin");
-                    memory[data_counter] = inp.as_bytes()[0];
+                    memory[data_counter] =
+                        match input.read_line(&mut inp).expect("Failed to read input") {
+                            0 => eof_policy.on_eof(memory[data_counter]),
+                            _ => inp.as_bytes()[0],
+                        };
                 }
-                ByteCode::JZ => {
-                    if memory[data_counter] == 0 {
-                        pc = jumptable[pc];
+                Op::JumpIfZero(target) => {
+                    let taken = memory[data_counter] == 0;
+                    observer.branch(pc, taken);
+                    if taken {
+                        pc = target;
                     }
                 }
-                ByteCode::JNZ => {
-                    if memory[data_counter] != 0 {
-                        pc = jumptable[pc];
+                Op::JumpIfNotZero(target) => {
+                    let taken = memory[data_counter] != 0;
+                    observer.branch(pc, taken);
+                    if taken {
+                        pc = target;
                     }
                 }
-                ByteCode::SETZERO => {
+                Op::SetZero => {
                     memory[data_counter] = 0;
                 }
-                ByteCode::MoveInStepUntilZero(chng) => {
-                    let cur_dc = &mut data_counter;
-                    while memory[*cur_dc] != 0 {
-                        *cur_dc = match chng {
-                            Change::Incr(x) => *cur_dc + x,
-                            Change::Decr(x) => *cur_dc - x,
-                        }
+                Op::ScanRight(x) => {
+                    while memory[data_counter] != 0 {
+                        data_counter += x;
+                    }
+                }
+                Op::ScanLeft(x) => {
+                    while memory[data_counter] != 0 {
+                        data_counter -= x;
+                    }
+                }
+                Op::MultiplyAdd(offset, factor) => {
+                    let value = memory[data_counter];
+                    if value != 0 {
+                        let target = data_counter.checked_add_signed(offset).unwrap();
+                        memory[target] = memory[target].wrapping_add(value.wrapping_mul(factor));
                     }
                 }
-                _ => unreachable!(),
             }
             pc += 1;
         }
-        println!("");
+        writeln!(output).expect("Failed to write output");
         //
     }
 }
@@ -181,8 +324,48 @@ impl ByteCodeProgram {
 #[cfg(test)]
 mod tests {
 
+    use super::ByteCode;
     use crate::parser::Parser;
 
+    #[test]
+    fn unmatched_brackets() {
+        for (code, unmatched) in [("+[[]-]", None), ("[]]", Some(2)), ("[[]", Some(0))] {
+            let prog = Parser::parse_to_bytecode(String::from(code));
+            assert_eq!(prog.unmatched_bracket(), unmatched, "{}", code);
+        }
+    }
+
+    #[test]
+    fn multiply_loops() {
+        let mut prog = Parser::parse_to_bytecode(String::from("+++[->++>+++<<]>[-<+>]"));
+        prog.opt_pass_1();
+        prog.opt_pass_2();
+        assert_eq!(
+            prog.instructions,
+            [
+                ByteCode::DataIncr(3),
+                ByteCode::MultiplyAdd(1, 2),
+                ByteCode::MultiplyAdd(2, 3),
+                ByteCode::SETZERO,
+                ByteCode::DataPointerIncr(1),
+                ByteCode::MultiplyAdd(-1, 1),
+                ByteCode::SETZERO,
+            ]
+        );
+        assert_eq!(prog.spans.len(), prog.instructions.len());
+        assert_eq!(prog.spans[1].start.col, 4);
+        assert_eq!(prog.spans[3].end.col, 15);
+
+        // The pointer moves, or the counter doesn't step by one.
+        for code in ["[->+<<]", "[-->+<]", "[->,<]"] {
+            let mut prog = Parser::parse_to_bytecode(String::from(code));
+            prog.opt_pass_1();
+            let before = prog.instructions.clone();
+            prog.opt_pass_2();
+            assert_eq!(prog.instructions, before);
+        }
+    }
+
     #[test]
     fn hello_world() {
         let code = include_str!("../programs/hello_world.bf");
@@ -0,0 +1,191 @@
+use std::fmt::Write;
+
+use crate::{
+    bytecode_bf::{ByteCode, ByteCodeProgram, Change},
+    io::EofPolicy,
+    jit_utils::JitOptions,
+    parser::Parser,
+    MEMORY_SIZE,
+};
+
+/// Bits per cell, wrapping around on overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    fn c_type(self) -> &'static str {
+        match self {
+            CellWidth::U8 => "uint8_t",
+            CellWidth::U16 => "uint16_t",
+            CellWidth::U32 => "uint32_t",
+        }
+    }
+}
+
+/// What only the C backend can be configured with.
+#[derive(Debug, Clone, Default)]
+pub struct COptions {
+    pub cell_width: CellWidth,
+}
+
+/// Turns bytecode into a standalone C program, to be built with any C
+/// compiler.
+pub struct CTranspiler {}
+
+impl CTranspiler {
+    /// Parses and optimizes `src` for `transpile`.
+    pub fn parse_and_transpile(src: String, options: &JitOptions, c_options: &COptions) -> String {
+        let mut prog = Parser::parse_to_bytecode(src);
+        prog.opt_pass_1();
+        // Multiply loops are only folded for the byte arithmetic they were
+        // matched with.
+        if c_options.cell_width == CellWidth::U8 {
+            prog.opt_pass_2();
+        }
+        Self::transpile(&prog, options, c_options)
+    }
+
+    /// C source for `prog`, with a `MEMORY_SIZE` tape of
+    /// `c_options.cell_width` cells and `getchar`/`putchar` I/O following
+    /// `options.eof_policy`. Like the original program, it doesn't check
+    /// that the pointer stays on the tape.
+    ///
+    /// `MultiplyAdd` needs 8-bit cells.
+    pub fn transpile(prog: &ByteCodeProgram, options: &JitOptions, c_options: &COptions) -> String {
+        let mut out = String::new();
+        let cell = c_options.cell_width.c_type();
+        writeln!(
+            out,
+            "/* Generated by bf_interpreter from {}. */",
+            options.source_name()
+        )
+        .unwrap();
+        out += "#include <stdint.h>\n#include <stdio.h>\n\n";
+        writeln!(out, "static {} tape[{}];\n", cell, MEMORY_SIZE).unwrap();
+        out += "int main(void) {\n";
+        writeln!(out, "    {} *p = tape;", cell).unwrap();
+        if prog.instructions.contains(&ByteCode::Read) {
+            out += "    int c;\n";
+        }
+
+        let mut depth = 1;
+        for instr in &prog.instructions {
+            if *instr == ByteCode::JNZ {
+                depth -= 1;
+            }
+            let statement = match *instr {
+                ByteCode::Nop => continue,
+                ByteCode::DataPointerIncr(n) => format!("p += {};", n),
+                ByteCode::DataPointerDecr(n) => format!("p -= {};", n),
+                ByteCode::DataIncr(n) => format!("*p += {};", n),
+                ByteCode::DataDecr(n) => format!("*p -= {};", n),
+                ByteCode::Write => String::from("putchar(*p);"),
+                ByteCode::Read => {
+                    let on_eof = match options.eof_policy {
+                        EofPolicy::Zero => " else *p = 0;",
+                        EofPolicy::AllOnes => " else *p = -1;",
+                        EofPolicy::Unchanged => "",
+                    };
+                    format!("if ((c = getchar()) != EOF) *p = c;{}", on_eof)
+                }
+                ByteCode::JZ => String::from("while (*p) {"),
+                ByteCode::JNZ => String::from("}"),
+                ByteCode::SETZERO => String::from("*p = 0;"),
+                ByteCode::MoveInStepUntilZero(Change::Incr(n)) => format!("while (*p) p += {};", n),
+                ByteCode::MoveInStepUntilZero(Change::Decr(n)) => format!("while (*p) p -= {};", n),
+                ByteCode::MultiplyAdd(offset, factor) => {
+                    if c_options.cell_width != CellWidth::U8 {
+                        panic!("MultiplyAdd needs 8-bit cells");
+                    }
+                    format!("if (*p) p[{}] += *p * {};", offset, factor)
+                }
+            };
+            writeln!(out, "{:width$}{}", "", statement, width = depth * 4).unwrap();
+            if *instr == ByteCode::JZ {
+                depth += 1;
+            }
+        }
+        out += "    return 0;\n}\n";
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    use super::{COptions, CTranspiler, CellWidth};
+    use crate::jit_utils::JitOptions;
+
+    /// Builds the C for `code` with `cc` and runs it on `input`.
+    fn run(name: &str, code: &str, input: &[u8], c_options: &COptions) -> Vec<u8> {
+        let dir = std::env::temp_dir();
+        let source = dir.join(format!("bf-c-{}-{}.c", name, std::process::id()));
+        let exe = source.with_extension("");
+        let c =
+            CTranspiler::parse_and_transpile(code.to_owned(), &JitOptions::default(), c_options);
+        std::fs::write(&source, c).unwrap();
+        let status = Command::new("cc")
+            .args(["-O1", "-Wall", "-Werror", "-o"])
+            .arg(&exe)
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let mut child = Command::new(&exe)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        std::fs::remove_file(&source).unwrap();
+        std::fs::remove_file(&exe).unwrap();
+        assert!(output.status.success());
+        output.stdout
+    }
+
+    #[test]
+    fn readable_output() {
+        let c = CTranspiler::parse_and_transpile(
+            String::from("+[->++<]>[>]<[,.]"),
+            &JitOptions::default(),
+            &COptions::default(),
+        );
+        let main = c.split("int main(void) {\n").nth(1).unwrap();
+        assert_eq!(
+            main,
+            "    uint8_t *p = tape;
+    int c;
+    *p += 1;
+    if (*p) p[1] += *p * 2;
+    *p = 0;
+    p += 1;
+    while (*p) p += 1;
+    p -= 1;
+    while (*p) {
+        if ((c = getchar()) != EOF) *p = c;
+        putchar(*p);
+    }
+    return 0;
+}
+"
+        );
+    }
+
+    #[test]
+    fn cell_width() {
+        // 256 wraps around to 0 in a byte, so only wider cells print 'A'
+        let code = format!("{}[[-]>{}.<]", "+".repeat(256), "+".repeat(65));
+        assert_eq!(run("u8", &code, b"", &COptions::default()), b"");
+        for cell_width in [CellWidth::U16, CellWidth::U32] {
+            assert_eq!(run("wide", &code, b"", &COptions { cell_width }), b"A");
+        }
+    }
+}
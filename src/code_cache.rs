@@ -0,0 +1,199 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Start of every cache entry, bumped whenever the entry layout changes.
+const MAGIC: &[u8; 8] = b"BFCACHE1";
+const EXTENSION: &str = "bfc";
+
+/// On-disk cache of compiled code.
+///
+/// An entry is stored under a hash of its key, and holds the full key too,
+/// so a hash collision is a miss rather than the wrong code. Entries are
+/// evicted least recently used first once the cache outgrows `max_size`.
+#[derive(Debug, Clone)]
+pub struct CodeCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl CodeCache {
+    pub const DEFAULT_MAX_SIZE: u64 = 64 << 20;
+
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64) -> Self {
+        CodeCache {
+            dir: dir.into(),
+            max_size,
+        }
+    }
+
+    /// `$BF_CACHE_DIR`, else `bf_interpreter` in `$XDG_CACHE_HOME` or `~/.cache`.
+    pub fn default_dir() -> PathBuf {
+        if let Some(dir) = std::env::var_os("BF_CACHE_DIR") {
+            return dir.into();
+        }
+        let cache_home = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME").unwrap_or_default()).join(".cache"),
+        };
+        cache_home.join("bf_interpreter")
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The code stored under `key`, if any. A hit counts as a use for eviction.
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let path = self.entry_path(key);
+        let entry = fs::read(&path).ok()?;
+        let code = Self::decode(&entry, key)?;
+        // Failing to update the time only makes the entry look older.
+        if let Ok(file) = File::options().write(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(code.to_vec())
+    }
+
+    /// Stores `code` under `key`, then evicts entries until the cache fits.
+    pub fn insert(&self, key: &[u8], code: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.entry_path(key);
+        let mut entry = Vec::with_capacity(MAGIC.len() + 8 + key.len() + code.len());
+        entry.extend_from_slice(MAGIC);
+        entry.extend_from_slice(&(key.len() as u64).to_le_bytes());
+        entry.extend_from_slice(key);
+        entry.extend_from_slice(code);
+        // Written aside and renamed, so other processes never see half of it.
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&tmp, &entry)?;
+        fs::rename(&tmp, &path)?;
+        self.evict(&path)
+    }
+
+    /// Number of entries and their total size in bytes.
+    pub fn stats(&self) -> io::Result<(usize, u64)> {
+        let entries = self.entries()?;
+        Ok((entries.len(), entries.iter().map(|e| e.1).sum()))
+    }
+
+    /// Removes every entry, returning how many there were.
+    pub fn clear(&self) -> io::Result<usize> {
+        let entries = self.entries()?;
+        for (path, _, _) in &entries {
+            fs::remove_file(path)?;
+        }
+        Ok(entries.len())
+    }
+
+    /// Removes the least recently used entries, other than `keep`, while the
+    /// cache is larger than `max_size`.
+    fn evict(&self, keep: &Path) -> io::Result<()> {
+        let mut entries = self.entries()?;
+        let mut total: u64 = entries.iter().map(|e| e.1).sum();
+        entries.sort_by_key(|e| e.2);
+        for (path, size, _) in entries {
+            if total <= self.max_size {
+                break;
+            }
+            if path == keep {
+                continue;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => total -= size,
+                // Another process got to it first.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => total -= size,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Path, size and last use of every entry.
+    fn entries(&self) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut entries = vec![];
+        for dir_entry in dir {
+            let path = dir_entry?.path();
+            if path.extension() != Some(EXTENSION.as_ref()) {
+                continue;
+            }
+            let metadata = fs::metadata(&path)?;
+            entries.push((path, metadata.len(), metadata.modified()?));
+        }
+        Ok(entries)
+    }
+
+    fn entry_path(&self, key: &[u8]) -> PathBuf {
+        self.dir.join(format!("{:016x}.{}", fnv1a(key), EXTENSION))
+    }
+
+    /// The code in `entry`, if it's a well-formed entry for `key`.
+    fn decode<'a>(entry: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+        let rest = entry.strip_prefix(MAGIC.as_slice())?;
+        let (len, rest) = rest.split_at_checked(8)?;
+        let len = u64::from_le_bytes(len.try_into().unwrap());
+        let (stored_key, code) = rest.split_at_checked(usize::try_from(len).ok()?)?;
+        (stored_key == key).then_some(code)
+    }
+}
+
+/// 64-bit FNV-1a; it only needs to spread keys over file names.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CodeCache;
+    use std::path::PathBuf;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bf-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn round_trip() {
+        let cache = CodeCache::new(test_dir("round_trip"), CodeCache::DEFAULT_MAX_SIZE);
+        assert_eq!(cache.get(b"key"), None);
+        cache.insert(b"key", b"code").unwrap();
+        assert_eq!(cache.get(b"key").as_deref(), Some(b"code".as_slice()));
+        assert_eq!(cache.get(b"other key"), None);
+        cache.insert(b"key", b"new code").unwrap();
+        assert_eq!(cache.get(b"key").as_deref(), Some(b"new code".as_slice()));
+
+        assert_eq!(cache.stats().unwrap().0, 1);
+        assert_eq!(cache.clear().unwrap(), 1);
+        assert_eq!(cache.get(b"key"), None);
+        std::fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn eviction() {
+        let code = [0; 100];
+        let cache = CodeCache::new(test_dir("eviction"), 250);
+        cache.insert(b"a", &code).unwrap();
+        cache.insert(b"b", &code).unwrap();
+        assert_eq!(cache.stats().unwrap().0, 2);
+        cache.insert(b"c", &code).unwrap();
+        let (count, size) = cache.stats().unwrap();
+        assert_eq!(count, 2);
+        assert!(size <= 250);
+        assert!(cache.get(b"c").is_some());
+
+        // An entry too big for the cache on its own is still kept.
+        cache.insert(b"d", &[0; 300]).unwrap();
+        assert_eq!(cache.stats().unwrap().0, 1);
+        assert!(cache.get(b"d").is_some());
+        std::fs::remove_dir_all(cache.dir()).unwrap();
+    }
+}
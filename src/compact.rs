@@ -0,0 +1,299 @@
+use crate::bytecode_bf::{ByteCode, ByteCodeProgram, Change};
+
+/// Bits of an operand, above the 8 bits of the opcode.
+const OPERAND_BITS: u32 = 24;
+const MAX_OPERAND: usize = (1 << OPERAND_BITS) - 1;
+
+const OP_NOP: u32 = 0;
+const OP_RIGHT: u32 = 1;
+const OP_LEFT: u32 = 2;
+const OP_ADD: u32 = 3;
+const OP_SUB: u32 = 4;
+const OP_WRITE: u32 = 5;
+const OP_READ: u32 = 6;
+const OP_JUMP_IF_ZERO: u32 = 7;
+const OP_JUMP_IF_NOT_ZERO: u32 = 8;
+const OP_SET_ZERO: u32 = 9;
+const OP_SCAN_RIGHT: u32 = 10;
+const OP_SCAN_LEFT: u32 = 11;
+const OP_MULTIPLY_ADD: u32 = 12;
+
+/// A `ByteCode` in 4 bytes: the opcode in the low byte, and a 24-bit
+/// operand above it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instr(u32);
+
+/// What an instruction does, as the interpreters match on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Nop,
+    Right(usize),
+    Left(usize),
+    Add(usize),
+    Sub(usize),
+    Write,
+    Read,
+    /// Jumps to the matching `JumpIfNotZero` if the cell is 0.
+    JumpIfZero(usize),
+    /// Jumps back to the matching `JumpIfZero` if the cell isn't 0.
+    JumpIfNotZero(usize),
+    SetZero,
+    ScanRight(usize),
+    ScanLeft(usize),
+    /// Cell offset and factor, like `ByteCode::MultiplyAdd`.
+    MultiplyAdd(isize, u8),
+}
+
+impl Op {
+    /// `prog` as `Op`s, with the jump targets in the brackets. Matches the
+    /// brackets with a stack in one pass, and panics if they don't match.
+    pub fn from_bytecode(prog: &ByteCodeProgram) -> Vec<Op> {
+        let mut ops = Vec::with_capacity(prog.instructions.len());
+        let mut open_brackets = vec![];
+        for (pc, instr) in prog.instructions.iter().enumerate() {
+            let op = match *instr {
+                ByteCode::Nop => Op::Nop,
+                ByteCode::DataPointerIncr(x) => Op::Right(x),
+                ByteCode::DataPointerDecr(x) => Op::Left(x),
+                ByteCode::DataIncr(x) => Op::Add(x),
+                ByteCode::DataDecr(x) => Op::Sub(x),
+                ByteCode::Write => Op::Write,
+                ByteCode::Read => Op::Read,
+                ByteCode::JZ => {
+                    // patched at the matching JNZ
+                    open_brackets.push(pc);
+                    Op::JumpIfZero(0)
+                }
+                ByteCode::JNZ => {
+                    let open = open_brackets
+                        .pop()
+                        .unwrap_or_else(|| panic!("unmatched ']' at pc= {}", pc));
+                    ops[open] = Op::JumpIfZero(pc);
+                    Op::JumpIfNotZero(open)
+                }
+                ByteCode::SETZERO => Op::SetZero,
+                ByteCode::MoveInStepUntilZero(Change::Incr(x)) => Op::ScanRight(x),
+                ByteCode::MoveInStepUntilZero(Change::Decr(x)) => Op::ScanLeft(x),
+                ByteCode::MultiplyAdd(offset, factor) => Op::MultiplyAdd(offset, factor),
+            };
+            ops.push(op);
+        }
+        if let Some(open) = open_brackets.pop() {
+            panic!("unmatched '[' at pc= {}", open);
+        }
+        ops
+    }
+}
+
+/// Instructions the interpreters step through by `pc`, decoded to `Op`s.
+pub trait Instructions {
+    fn get(&self, pc: usize) -> Option<Op>;
+}
+
+impl Instructions for [Op] {
+    #[inline]
+    fn get(&self, pc: usize) -> Option<Op> {
+        <[Op]>::get(self, pc).copied()
+    }
+}
+
+impl Instructions for CompactProgram {
+    #[inline]
+    fn get(&self, pc: usize) -> Option<Op> {
+        self.instructions.get(pc).map(|instr| instr.decode())
+    }
+}
+
+impl Instr {
+    fn new(opcode: u32, operand: usize) -> Result<Self, String> {
+        if operand > MAX_OPERAND {
+            return Err(format!(
+                "{} doesn't fit in the {} bits of a compact instruction",
+                operand, OPERAND_BITS
+            ));
+        }
+        Ok(Instr(opcode | (operand as u32) << 8))
+    }
+
+    /// Cells wrap, so only `x % 256` of a `+` or `-` run matters.
+    fn cell_change(opcode: u32, x: usize) -> Self {
+        Instr(opcode | ((x % 256) as u32) << 8)
+    }
+
+    fn operand(self) -> u32 {
+        self.0 >> 8
+    }
+
+    #[inline]
+    pub fn decode(self) -> Op {
+        let operand = self.operand() as usize;
+        match self.0 & 0xFF {
+            OP_NOP => Op::Nop,
+            OP_RIGHT => Op::Right(operand),
+            OP_LEFT => Op::Left(operand),
+            OP_ADD => Op::Add(operand),
+            OP_SUB => Op::Sub(operand),
+            OP_WRITE => Op::Write,
+            OP_READ => Op::Read,
+            OP_JUMP_IF_ZERO => Op::JumpIfZero(operand),
+            OP_JUMP_IF_NOT_ZERO => Op::JumpIfNotZero(operand),
+            OP_SET_ZERO => Op::SetZero,
+            OP_SCAN_RIGHT => Op::ScanRight(operand),
+            OP_SCAN_LEFT => Op::ScanLeft(operand),
+            OP_MULTIPLY_ADD => Op::MultiplyAdd((operand >> 8) as i16 as isize, operand as u8),
+            opcode => unreachable!("invalid opcode {}", opcode),
+        }
+    }
+}
+
+/// A `ByteCodeProgram` as `Instr`s, a quarter of its size, with the jump
+/// targets in the brackets. Instruction `pc` is instruction `pc` of the
+/// program, so observers see the same `pc`s and spans.
+pub struct CompactProgram {
+    pub instructions: Vec<Instr>,
+}
+
+impl CompactProgram {
+    /// Encodes `prog`, with the jumps from `Op::from_bytecode`. Fails if a
+    /// jump target, pointer move or scan step doesn't fit in 24 bits, or a
+    /// multiply loop offset in 16. Those programs can still run as `Op`s.
+    pub fn from_bytecode(prog: &ByteCodeProgram) -> Result<Self, String> {
+        let instructions = Op::from_bytecode(prog)
+            .into_iter()
+            .enumerate()
+            .map(|(pc, op)| {
+                let instr = match op {
+                    Op::Nop => Instr::new(OP_NOP, 0),
+                    Op::Right(x) => Instr::new(OP_RIGHT, x),
+                    Op::Left(x) => Instr::new(OP_LEFT, x),
+                    Op::Add(x) => Ok(Instr::cell_change(OP_ADD, x)),
+                    Op::Sub(x) => Ok(Instr::cell_change(OP_SUB, x)),
+                    Op::Write => Instr::new(OP_WRITE, 0),
+                    Op::Read => Instr::new(OP_READ, 0),
+                    Op::JumpIfZero(target) => Instr::new(OP_JUMP_IF_ZERO, target),
+                    Op::JumpIfNotZero(target) => Instr::new(OP_JUMP_IF_NOT_ZERO, target),
+                    Op::SetZero => Instr::new(OP_SET_ZERO, 0),
+                    Op::ScanRight(x) => Instr::new(OP_SCAN_RIGHT, x),
+                    Op::ScanLeft(x) => Instr::new(OP_SCAN_LEFT, x),
+                    Op::MultiplyAdd(offset, factor) => i16::try_from(offset)
+                        .map_err(|_| format!("multiply loop offset {} is too large", offset))
+                        .and_then(|offset| {
+                            Instr::new(
+                                OP_MULTIPLY_ADD,
+                                (offset as u16 as usize) << 8 | factor as usize,
+                            )
+                        }),
+                };
+                instr.map_err(|e| format!("{} at pc= {}", e, pc))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(CompactProgram { instructions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompactProgram, Instr, Op};
+    use crate::bytecode_bf::{ByteCode, ByteCodeProgram, Change};
+    use crate::parser::Parser;
+
+    fn ops(code: &str) -> Vec<Op> {
+        let mut prog = Parser::parse_to_bytecode(code.to_owned());
+        prog.opt_pass_1();
+        prog.opt_pass_2();
+        let compact = CompactProgram::from_bytecode(&prog).unwrap();
+        compact
+            .instructions
+            .iter()
+            .map(|instr| instr.decode())
+            .collect()
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(std::mem::size_of::<Instr>(), 4);
+        assert_eq!(
+            ops("+++[->++>+++<<]>[-<+>]<<<-.,[>]"),
+            [
+                Op::Add(3),
+                Op::MultiplyAdd(1, 2),
+                Op::MultiplyAdd(2, 3),
+                Op::SetZero,
+                Op::Right(1),
+                Op::MultiplyAdd(-1, 1),
+                Op::SetZero,
+                Op::Left(3),
+                Op::Sub(1),
+                Op::Write,
+                Op::Read,
+                Op::ScanRight(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn jump_targets() {
+        assert_eq!(
+            ops("[[,]+[-.]]"),
+            [
+                Op::JumpIfZero(9),
+                Op::JumpIfZero(3),
+                Op::Read,
+                Op::JumpIfNotZero(1),
+                Op::Add(1),
+                Op::JumpIfZero(8),
+                Op::Sub(1),
+                Op::Write,
+                Op::JumpIfNotZero(5),
+                Op::JumpIfNotZero(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let depth = 100_000;
+        let code = "[".repeat(depth) + &"]".repeat(depth);
+        let ops = ops(&code);
+        assert_eq!(ops[0], Op::JumpIfZero(2 * depth - 1));
+        assert_eq!(ops[depth], Op::JumpIfNotZero(depth - 1));
+    }
+
+    #[test]
+    fn large_operands() {
+        let prog = |instructions| ByteCodeProgram {
+            instructions,
+            spans: vec![],
+        };
+        // runs of 256 and more wrap
+        let cells = prog(vec![
+            ByteCode::DataIncr(255),
+            ByteCode::DataIncr(1 << 24 | 3),
+            ByteCode::DataDecr(256),
+        ]);
+        let compact = CompactProgram::from_bytecode(&cells).unwrap();
+        assert_eq!(
+            compact
+                .instructions
+                .iter()
+                .map(|instr| instr.decode())
+                .collect::<Vec<_>>(),
+            [Op::Add(255), Op::Add(3), Op::Sub(0)]
+        );
+
+        for instr in [
+            ByteCode::DataPointerIncr(1 << 24),
+            ByteCode::MoveInStepUntilZero(Change::Decr(1 << 24)),
+            ByteCode::MultiplyAdd(1 << 15, 1),
+        ] {
+            let wide = prog(vec![ByteCode::DataIncr(1), instr]);
+            assert!(CompactProgram::from_bytecode(&wide).is_err(), "{:?}", instr);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn unmatched() {
+        ops("[[]");
+    }
+}
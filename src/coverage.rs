@@ -0,0 +1,186 @@
+use std::{collections::BTreeMap, io::Write};
+
+use crate::{
+    bf::Program,
+    bytecode_bf::{ByteCode, ByteCodeProgram},
+    observer::ExecutionObserver,
+    parser::Span,
+};
+
+/// Execution counts gathered by the interpreters, keyed by instruction index.
+///
+/// Every instruction remembers the source span it came from so the counts can
+/// be reported per line of the `.bf` file in lcov format.
+pub struct Coverage {
+    spans: Vec<Span>,
+    hits: Vec<u64>,
+    // Only present for `[` / `]`: [jump taken, fell through]
+    branches: Vec<Option<[u64; 2]>>,
+}
+
+impl Coverage {
+    fn new(spans: Vec<Span>, is_branch: impl Iterator<Item = bool>) -> Self {
+        Coverage {
+            hits: vec![0; spans.len()],
+            branches: is_branch
+                .map(|b| if b { Some([0, 0]) } else { None })
+                .collect(),
+            spans,
+        }
+    }
+
+    pub fn for_program(prog: &Program) -> Self {
+        let spans = prog
+            .locations
+            .iter()
+            .map(|loc| Span {
+                start: *loc,
+                end: *loc,
+            })
+            .collect();
+        Self::new(
+            spans,
+            prog.instructions.iter().map(|c| *c == '[' || *c == ']'),
+        )
+    }
+
+    pub fn for_bytecode(prog: &ByteCodeProgram) -> Self {
+        Self::new(
+            prog.spans.clone(),
+            prog.instructions
+                .iter()
+                .map(|x| matches!(x, ByteCode::JZ | ByteCode::JNZ)),
+        )
+    }
+
+    pub fn hits(&self, pc: usize) -> u64 {
+        self.hits[pc]
+    }
+
+    /// `[jump taken, fell through]` counts of the bracket at `pc`.
+    pub fn branch_counts(&self, pc: usize) -> Option<[u64; 2]> {
+        self.branches[pc]
+    }
+
+    /// Hit count of every source line that holds at least one instruction.
+    /// A line counts as often as its most executed instruction.
+    pub fn line_hits(&self) -> BTreeMap<usize, u64> {
+        let mut lines = BTreeMap::new();
+        for (span, hits) in self.spans.iter().zip(&self.hits) {
+            for line in span.start.line..=span.end.line {
+                let entry = lines.entry(line).or_insert(0);
+                *entry = (*entry).max(*hits);
+            }
+        }
+        lines
+    }
+
+    /// Writes a single lcov record for `source_file`.
+    ///
+    /// Each bracket is reported as its own branch block (numbered by
+    /// instruction index) with branch 0 being the jump and branch 1 the fall
+    /// through, so `[` shows loop skipped/entered and `]` shows
+    /// repeated/exited.
+    pub fn write_lcov<W: Write>(&self, source_file: &str, out: &mut W) -> std::io::Result<()> {
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", source_file)?;
+
+        let mut branches_found = 0;
+        let mut branches_hit = 0;
+        for (pc, counts) in self.branches.iter().enumerate() {
+            if let Some(counts) = counts {
+                let line = self.spans[pc].start.line;
+                for (branch, count) in counts.iter().enumerate() {
+                    branches_found += 1;
+                    if self.hits[pc] == 0 {
+                        writeln!(out, "BRDA:{},{},{},-", line, pc, branch)?;
+                    } else {
+                        writeln!(out, "BRDA:{},{},{},{}", line, pc, branch, count)?;
+                    }
+                    if *count > 0 {
+                        branches_hit += 1;
+                    }
+                }
+            }
+        }
+        writeln!(out, "BRF:{}", branches_found)?;
+        writeln!(out, "BRH:{}", branches_hit)?;
+
+        let lines = self.line_hits();
+        for (line, hits) in &lines {
+            writeln!(out, "DA:{},{}", line, hits)?;
+        }
+        writeln!(out, "LF:{}", lines.len())?;
+        writeln!(out, "LH:{}", lines.values().filter(|x| **x > 0).count())?;
+        writeln!(out, "end_of_record")?;
+        Ok(())
+    }
+}
+
+impl ExecutionObserver for Coverage {
+    fn instruction(&mut self, pc: usize) {
+        self.hits[pc] += 1;
+    }
+
+    fn branch(&mut self, pc: usize, taken: bool) {
+        if let Some(counts) = &mut self.branches[pc] {
+            counts[if taken { 0 } else { 1 }] += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::Parser;
+
+    // Line 3 is never reached since the loop on line 2 exits with cell 0 = 0.
+    const CODE: &str = "++\n[-]\n[>+<-]\n";
+
+    #[test]
+    fn interpreter_coverage() {
+        let prog = Parser::parse(CODE.to_owned());
+        let coverage = prog.eval_with_coverage();
+
+        assert_eq!(coverage.hits(0), 1);
+        // '[' on line 2: entered once, never skipped
+        assert_eq!(coverage.branch_counts(2), Some([0, 1]));
+        // ']' on line 2: jumped back once, fell through once
+        assert_eq!(coverage.branch_counts(4), Some([1, 1]));
+        assert_eq!(coverage.hits(6), 0);
+        assert_eq!(coverage.branch_counts(5), Some([1, 0]));
+        assert_eq!(coverage.branch_counts(0), None);
+
+        let lines = coverage.line_hits();
+        assert_eq!(lines.get(&1), Some(&1));
+        assert_eq!(lines.get(&2), Some(&2));
+        assert_eq!(lines.get(&3), Some(&1));
+    }
+
+    #[test]
+    fn bytecode_coverage() {
+        let prog = Parser::parse_to_bytecode(CODE.to_owned());
+        let coverage = prog.eval_with_coverage();
+
+        // DataIncr(2) runs once, the body of the first loop twice
+        assert_eq!(coverage.hits(0), 1);
+        assert_eq!(coverage.hits(2), 2);
+        assert_eq!(coverage.branch_counts(1), Some([0, 1]));
+    }
+
+    #[test]
+    fn lcov_output() {
+        let prog = Parser::parse(CODE.to_owned());
+        let coverage = prog.eval_with_coverage();
+        let mut out = vec![];
+        coverage.write_lcov("test.bf", &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.starts_with("TN:\nSF:test.bf\n"));
+        assert!(out.contains("BRDA:2,2,0,0\n"));
+        assert!(out.contains("BRDA:2,2,1,1\n"));
+        assert!(out.contains("BRDA:3,10,0,-\n"));
+        assert!(out.contains("BRF:8\nBRH:4\n"));
+        assert!(out.contains("DA:1,1\nDA:2,2\nDA:3,1\nLF:3\nLH:3\n"));
+        assert!(out.ends_with("end_of_record\n"));
+    }
+}
@@ -0,0 +1,402 @@
+use std::io::{Read, Write};
+
+use cranelift_codegen::control::ControlPlane;
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{
+    types, AbiParam, Block, Function, InstBuilder, MemFlags, Signature, UserFuncName, Value,
+};
+use cranelift_codegen::isa::OwnedTargetIsa;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+
+use crate::{
+    bytecode_bf::{ByteCode, ByteCodeProgram, Change},
+    disasm,
+    io::{self, Io},
+    jit_utils::{
+        CodeRegion, JitOptions, JitProgram, JitResult, StageTimings, STATUS_OK,
+        STATUS_OUT_OF_BOUNDS,
+    },
+    parser::Parser,
+    perf_map, MEMORY_SIZE,
+};
+
+/// Compiles bytecode with Cranelift: quicker to compile than LLVM, with
+/// fewer optimizations, and the same ABI.
+///
+/// The code is `JitResult bf_program(u8 *tape, i64 tape_len, u8 *io)`,
+/// calling `io::bf_write` and `io::bf_read` through their addresses, so it
+/// can run from any executable memory.
+pub struct CraneliftJit {
+    isa: OwnedTargetIsa,
+}
+
+impl Default for CraneliftJit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CraneliftJit {
+    /// A JIT for the host, optimizing for speed.
+    pub fn new() -> Self {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").unwrap();
+        let isa = cranelift_native::builder()
+            .unwrap_or_else(|e| panic!("Cranelift doesn't support this host: {}", e))
+            .finish(settings::Flags::new(flags))
+            .expect("Failed to create the Cranelift target");
+        CraneliftJit { isa }
+    }
+
+    pub fn parse_and_run(src: String) {
+        Self::parse_and_run_with_options(src, &JitOptions::default());
+    }
+
+    /// Runs `src` on stdin and stdout, like the LLVM JIT does.
+    pub fn parse_and_run_with_options(src: String, options: &JitOptions) {
+        let mut prog = Parser::parse_to_bytecode(src);
+        prog.opt_pass_1();
+        prog.opt_pass_2();
+        let jit = Self::new();
+        if options.dump_asm {
+            let mut timings = StageTimings::new(false);
+            let code = jit.compile::<std::io::StdinLock, std::io::StdoutLock>(&prog, &mut timings);
+            print!(
+                "{}",
+                disasm::disassemble(&code, &[(0, String::from("bf_program"))])
+            );
+            return;
+        }
+        let mut tape = vec![0; MEMORY_SIZE];
+        let (stdin, stdout) = (std::io::stdin(), std::io::stdout());
+        let mut io = Io::new(stdin.lock(), stdout.lock(), options.eof_policy);
+        let result = jit.execute(&prog, &mut tape, &mut io, options);
+        io.finish().expect("Failed to write output");
+        if result.status == STATUS_OUT_OF_BOUNDS {
+            panic!("Data pointer out of bounds: {}", result.dataptr);
+        }
+    }
+
+    /// Compiles and runs `prog` on `tape`, which the program sees as zeroed
+    /// memory it can't move past, and which keeps its contents afterwards.
+    /// `options.eof_policy` is ignored, `io` has its own.
+    pub fn execute<R: Read, W: Write>(
+        &self,
+        prog: &ByteCodeProgram,
+        tape: &mut [u8],
+        io: &mut Io<R, W>,
+        options: &JitOptions,
+    ) -> JitResult {
+        let (result, timings) = self.execute_timed(prog, tape, io, options);
+        timings.report();
+        result
+    }
+
+    /// `execute`, returning the timings instead of reporting them.
+    pub fn execute_timed<R: Read, W: Write>(
+        &self,
+        prog: &ByteCodeProgram,
+        tape: &mut [u8],
+        io: &mut Io<R, W>,
+        options: &JitOptions,
+    ) -> (JitResult, StageTimings) {
+        let mut timings = StageTimings::new(options.time_stages);
+        let code = self.compile::<R, W>(prog, &mut timings);
+        timings.set_code_size(code.len());
+        let program = timings.time("load", || JitProgram::new(code));
+        if options.perf_map {
+            let region = CodeRegion {
+                name: String::from("bf_program"),
+                start: 0,
+                end: program.program_size(),
+            };
+            perf_map::append_to_perf_map(program.program_memory() as usize, &[region])
+                .expect("Failed to write perf map");
+        }
+        let result = unsafe {
+            let bf_fn: unsafe extern "C" fn(*mut u8, i64, *mut u8) -> JitResult =
+                std::mem::transmute(program.program_memory());
+            let (memory, len) = (tape.as_mut_ptr(), tape.len() as i64);
+            let io = io as *mut Io<R, W> as *mut u8;
+            timings.time("execute", || bf_fn(memory, len, io))
+        };
+        (result, timings)
+    }
+
+    /// Machine code for `prog`, calling the runtime functions for an
+    /// `Io<R, W>`.
+    fn compile<R: Read, W: Write>(
+        &self,
+        prog: &ByteCodeProgram,
+        timings: &mut StageTimings,
+    ) -> Vec<u8> {
+        let function = timings.time("irgen", || {
+            self.build_function(
+                prog,
+                io::bf_write::<R, W> as *const () as i64,
+                io::bf_read::<R, W> as *const () as i64,
+            )
+        });
+        let mut context = Context::for_function(function);
+        let compiled = timings
+            .time("codegen", || {
+                context.compile(&*self.isa, &mut ControlPlane::default())
+            })
+            .unwrap_or_else(|e| panic!("Failed to compile with Cranelift: {:?}", e.inner));
+        // Only true for code that reaches everything through registers,
+        // which is what lets it run from wherever it gets copied.
+        assert!(compiled.buffer.relocs().is_empty());
+        compiled.code_buffer().to_vec()
+    }
+
+    fn build_function(&self, prog: &ByteCodeProgram, bf_write: i64, bf_read: i64) -> Function {
+        let ptr = self.isa.pointer_type();
+        let call_conv = self.isa.default_call_conv();
+        let mut signature = Signature::new(call_conv);
+        signature.params.push(AbiParam::new(ptr));
+        signature.params.push(AbiParam::new(types::I64));
+        signature.params.push(AbiParam::new(ptr));
+        // `JitResult`, which the C ABI returns in two registers
+        signature.returns.push(AbiParam::new(types::I64));
+        signature.returns.push(AbiParam::new(types::I32));
+        let mut function = Function::with_name_signature(UserFuncName::user(0, 0), signature);
+
+        let mut function_context = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut function, &mut function_context);
+        // bytes get zero-extended across calls, as C compilers expect
+        let byte = AbiParam::new(types::I8).uext();
+        let mut write_signature = Signature::new(call_conv);
+        write_signature.params.push(AbiParam::new(ptr));
+        write_signature.params.push(byte);
+        let write_signature = builder.import_signature(write_signature);
+        let mut read_signature = Signature::new(call_conv);
+        read_signature.params.push(AbiParam::new(ptr));
+        read_signature.params.push(byte);
+        read_signature.returns.push(byte);
+        let read_signature = builder.import_signature(read_signature);
+
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let params = builder.block_params(entry).to_vec();
+        let out_of_bounds = builder.create_block();
+        builder.append_block_param(out_of_bounds, types::I64);
+        let tape = Tape {
+            memory: params[0],
+            len: params[1],
+            dataptr: Variable::from_u32(0),
+            out_of_bounds,
+        };
+        let io = params[2];
+        builder.declare_var(tape.dataptr, types::I64);
+        let zero = builder.ins().iconst(types::I64, 0);
+        builder.def_var(tape.dataptr, zero);
+
+        // (loop body, block after the loop)
+        let mut loops: Vec<(Block, Block)> = vec![];
+        let flags = MemFlags::trusted();
+        for instruction in &prog.instructions {
+            match *instruction {
+                ByteCode::Nop => {}
+                ByteCode::DataPointerIncr(n) | ByteCode::DataPointerDecr(n) => {
+                    let dataptr = builder.use_var(tape.dataptr);
+                    let n = n as i64;
+                    let dataptr = match instruction {
+                        ByteCode::DataPointerIncr(_) => builder.ins().iadd_imm(dataptr, n),
+                        _ => builder.ins().iadd_imm(dataptr, -n),
+                    };
+                    builder.def_var(tape.dataptr, dataptr);
+                    let next = builder.create_block();
+                    tape.branch_if_in_bounds(&mut builder, dataptr, next);
+                    builder.switch_to_block(next);
+                }
+                ByteCode::DataIncr(delta) | ByteCode::DataDecr(delta) => {
+                    let delta = match instruction {
+                        ByteCode::DataIncr(_) => delta as u8,
+                        _ => (delta as u8).wrapping_neg(),
+                    };
+                    let cell = tape.cell(&mut builder, 0);
+                    let value = builder.ins().load(types::I8, flags, cell, 0);
+                    let value = builder.ins().iadd_imm(value, delta as i64);
+                    builder.ins().store(flags, value, cell, 0);
+                }
+                ByteCode::Write => {
+                    let cell = tape.cell(&mut builder, 0);
+                    let value = builder.ins().load(types::I8, flags, cell, 0);
+                    let callee = builder.ins().iconst(ptr, bf_write);
+                    builder
+                        .ins()
+                        .call_indirect(write_signature, callee, &[io, value]);
+                }
+                ByteCode::Read => {
+                    let cell = tape.cell(&mut builder, 0);
+                    let current = builder.ins().load(types::I8, flags, cell, 0);
+                    let callee = builder.ins().iconst(ptr, bf_read);
+                    let call = builder
+                        .ins()
+                        .call_indirect(read_signature, callee, &[io, current]);
+                    let value = builder.inst_results(call)[0];
+                    builder.ins().store(flags, value, cell, 0);
+                }
+                ByteCode::JZ => {
+                    let body = builder.create_block();
+                    let end = builder.create_block();
+                    let value = tape.load(&mut builder);
+                    builder.ins().brif(value, body, &[], end, &[]);
+                    builder.switch_to_block(body);
+                    loops.push((body, end));
+                }
+                ByteCode::JNZ => {
+                    let (body, end) = loops.pop().expect("Invalid program");
+                    let value = tape.load(&mut builder);
+                    builder.ins().brif(value, body, &[], end, &[]);
+                    builder.switch_to_block(end);
+                }
+                ByteCode::SETZERO => {
+                    let cell = tape.cell(&mut builder, 0);
+                    let zero = builder.ins().iconst(types::I8, 0);
+                    builder.ins().store(flags, zero, cell, 0);
+                }
+                ByteCode::MoveInStepUntilZero(change) => {
+                    // while memory[dataptr] != 0 { dataptr (+/-)= step; }
+                    let scan = builder.create_block();
+                    let step = builder.create_block();
+                    let end = builder.create_block();
+                    builder.ins().jump(scan, &[]);
+
+                    builder.switch_to_block(scan);
+                    let value = tape.load(&mut builder);
+                    builder.ins().brif(value, step, &[], end, &[]);
+
+                    builder.switch_to_block(step);
+                    let dataptr = builder.use_var(tape.dataptr);
+                    let dataptr = match change {
+                        Change::Incr(n) => builder.ins().iadd_imm(dataptr, n as i64),
+                        Change::Decr(n) => builder.ins().iadd_imm(dataptr, -(n as i64)),
+                    };
+                    builder.def_var(tape.dataptr, dataptr);
+                    tape.branch_if_in_bounds(&mut builder, dataptr, scan);
+
+                    builder.switch_to_block(end);
+                }
+                ByteCode::MultiplyAdd(offset, factor) => {
+                    // if memory[dataptr] != 0 {
+                    //     memory[dataptr + offset] += memory[dataptr] * factor;
+                    // }
+                    // Only a loop that runs at all moves the pointer to the
+                    // target, so going off the tape is reported at the target.
+                    let multiply = builder.create_block();
+                    let add = builder.create_block();
+                    let end = builder.create_block();
+                    let value = tape.load(&mut builder);
+                    builder.ins().brif(value, multiply, &[], end, &[]);
+
+                    builder.switch_to_block(multiply);
+                    let dataptr = builder.use_var(tape.dataptr);
+                    let target = builder.ins().iadd_imm(dataptr, offset as i64);
+                    tape.branch_if_in_bounds(&mut builder, target, add);
+
+                    builder.switch_to_block(add);
+                    let cell = tape.cell(&mut builder, offset as i64);
+                    let current = builder.ins().load(types::I8, flags, cell, 0);
+                    let product = builder.ins().imul_imm(value, factor as i64);
+                    let sum = builder.ins().iadd(current, product);
+                    builder.ins().store(flags, sum, cell, 0);
+                    builder.ins().jump(end, &[]);
+
+                    builder.switch_to_block(end);
+                }
+            }
+        }
+        let dataptr = builder.use_var(tape.dataptr);
+        let status = builder.ins().iconst(types::I32, STATUS_OK as i64);
+        builder.ins().return_(&[dataptr, status]);
+
+        builder.switch_to_block(out_of_bounds);
+        let dataptr = builder.block_params(out_of_bounds)[0];
+        let status = builder
+            .ins()
+            .iconst(types::I32, STATUS_OUT_OF_BOUNDS as i64);
+        builder.ins().return_(&[dataptr, status]);
+
+        builder.seal_all_blocks();
+        builder.finalize();
+        function
+    }
+}
+
+struct Tape {
+    memory: Value,
+    len: Value,
+    /// Index of the current cell.
+    dataptr: Variable,
+    /// Returns `STATUS_OUT_OF_BOUNDS` with the data pointer it gets passed.
+    out_of_bounds: Block,
+}
+
+impl Tape {
+    /// Address of the cell `offset` cells from the current one.
+    fn cell(&self, builder: &mut FunctionBuilder, offset: i64) -> Value {
+        let dataptr = builder.use_var(self.dataptr);
+        let dataptr = builder.ins().iadd_imm(dataptr, offset);
+        builder.ins().iadd(self.memory, dataptr)
+    }
+
+    /// The current cell.
+    fn load(&self, builder: &mut FunctionBuilder) -> Value {
+        let cell = self.cell(builder, 0);
+        builder.ins().load(types::I8, MemFlags::trusted(), cell, 0)
+    }
+
+    /// Branches to `next` if `dataptr` is within the tape, and leaves
+    /// otherwise. Negative values wrap around, so one unsigned comparison
+    /// covers both ends.
+    fn branch_if_in_bounds(&self, builder: &mut FunctionBuilder, dataptr: Value, next: Block) {
+        let in_bounds = builder
+            .ins()
+            .icmp(IntCC::UnsignedLessThan, dataptr, self.len);
+        builder
+            .ins()
+            .brif(in_bounds, next, &[], self.out_of_bounds, &[dataptr]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CraneliftJit;
+    use crate::io::{EofPolicy, Io};
+    use crate::jit_utils::{JitOptions, STATUS_OK, STATUS_OUT_OF_BOUNDS};
+    use crate::parser::Parser;
+
+    fn run(code: &str, input: &[u8], tape: &mut [u8]) -> (Vec<u8>, i64, i32) {
+        let mut prog = Parser::parse_to_bytecode(code.to_owned());
+        prog.opt_pass_1();
+        prog.opt_pass_2();
+        let mut output = vec![];
+        let mut io = Io::new(input, &mut output, EofPolicy::Zero);
+        let result = CraneliftJit::new().execute(&prog, tape, &mut io, &JitOptions::default());
+        io.finish().unwrap();
+        (output, result.dataptr, result.status)
+    }
+
+    #[test]
+    fn hello_world() {
+        let code = include_str!("../programs/hello_world.bf");
+        let (output, _, status) = run(code, b"", &mut [0; 100]);
+        assert_eq!(output, b"Hello World!\n");
+        assert_eq!(status, STATUS_OK);
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        assert_eq!(run("<", b"", &mut [0; 4]).1, -1);
+        assert_eq!(run("+[>+]", b"", &mut [0; 4]).2, STATUS_OUT_OF_BOUNDS);
+        // a multiply loop reaching past the end
+        let (_, dataptr, status) = run("+[->>>>+<<<<]", b"", &mut [0; 4]);
+        assert_eq!((dataptr, status), (4, STATUS_OUT_OF_BOUNDS));
+        // but not when it doesn't run
+        assert_eq!(run("[->>>>+<<<<]", b"", &mut [0; 4]).2, STATUS_OK);
+    }
+}
@@ -0,0 +1,63 @@
+use std::fmt::Write;
+
+use iced_x86::{Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter};
+
+/// Disassembles generated x86-64 code, with addresses relative to the start
+/// of `code`.
+///
+/// `annotations` are `(code offset, text)` pairs sorted by offset, typically
+/// the bf instruction or bytecode op whose code starts at that offset. Each
+/// one is printed as a comment line before the first native instruction at or
+/// after its offset.
+pub fn disassemble(code: &[u8], annotations: &[(usize, String)]) -> String {
+    let mut decoder = Decoder::with_ip(64, code, 0, DecoderOptions::NONE);
+    let mut formatter = IntelFormatter::new();
+    let mut instruction = Instruction::default();
+    let mut annotations = annotations.iter().peekable();
+    let mut out = String::new();
+    let mut text = String::new();
+
+    while decoder.can_decode() {
+        decoder.decode_out(&mut instruction);
+        let start = instruction.ip() as usize;
+        let end = start + instruction.len();
+        while let Some((_, note)) = annotations.next_if(|(offset, _)| *offset < end) {
+            writeln!(out, "; {}", note).unwrap();
+        }
+
+        text.clear();
+        formatter.format(&instruction, &mut text);
+        let bytes: Vec<String> = code[start..end]
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect();
+        writeln!(out, "{:8x}:  {:<30} {}", start, bytes.join(" "), text).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::disassemble;
+
+    #[test]
+    fn annotated_listing() {
+        let code = [
+            0x48, 0x89, 0xf8, // mov %rdi, %rax
+            0x48, 0x83, 0xc0, 0x04, // add $4, %rax
+            0xc3, // ret
+        ];
+        let listing = disassemble(
+            &code,
+            &[(0, "'>' at 1:1".to_owned()), (7, "epilogue".to_owned())],
+        );
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "; '>' at 1:1");
+        assert!(lines[1].starts_with("       0:  48 89 f8"));
+        assert!(lines[1].ends_with("mov rax,rdi"));
+        assert!(lines[2].starts_with("       3:  48 83 c0 04"));
+        assert_eq!(lines[3], "; epilogue");
+        assert!(lines[4].ends_with("ret"));
+    }
+}
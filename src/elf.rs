@@ -0,0 +1,393 @@
+//! Minimal ELF64 (x86-64, little endian) writer.
+//!
+//! Only what the JITs need to describe their code to other tools: sections,
+//! a symbol table and the string tables that go with them, plus the program
+//! headers of a static executable.
+
+pub const ET_EXEC: u16 = 2;
+
+pub const SHT_PROGBITS: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHT_NOBITS: u32 = 8;
+
+pub const SHF_WRITE: u64 = 0x1;
+pub const SHF_ALLOC: u64 = 0x2;
+pub const SHF_EXECINSTR: u64 = 0x4;
+
+pub const STB_GLOBAL: u8 = 1;
+pub const STT_FUNC: u8 = 2;
+
+pub const PT_LOAD: u32 = 1;
+
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+pub const PF_R: u32 = 0x4;
+
+pub const PAGE_SIZE: u64 = 0x1000;
+
+const EM_X86_64: u16 = 62;
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub flags: u64,
+    pub addr: u64,
+    /// File contents, empty for `SHT_NOBITS`.
+    pub data: Vec<u8>,
+    /// Only used for `SHT_NOBITS`, other sections are as big as their data.
+    pub nobits_size: u64,
+    pub align: u64,
+}
+
+impl Section {
+    pub fn progbits(name: &str, flags: u64, addr: u64, data: Vec<u8>) -> Self {
+        Section {
+            name: name.to_owned(),
+            kind: SHT_PROGBITS,
+            flags,
+            addr,
+            data,
+            nobits_size: 0,
+            align: 1,
+        }
+    }
+
+    pub fn nobits(name: &str, flags: u64, addr: u64, size: u64) -> Self {
+        Section {
+            name: name.to_owned(),
+            kind: SHT_NOBITS,
+            flags,
+            addr,
+            data: vec![],
+            nobits_size: size,
+            align: 1,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        if self.kind == SHT_NOBITS {
+            self.nobits_size
+        } else {
+            self.data.len() as u64
+        }
+    }
+}
+
+pub struct Symbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+    /// Index returned by `ElfBuilder::add_section`.
+    pub section: u16,
+    pub kind: u8,
+}
+
+/// A `PT_LOAD` segment mapping one section at its address. Sections with
+/// file contents need an offset in the file congruent to their address
+/// modulo `PAGE_SIZE`, which aligning them to a page gives.
+pub struct Segment {
+    /// Index returned by `ElfBuilder::add_section`.
+    pub section: u16,
+    /// `PF_*`
+    pub flags: u32,
+}
+
+struct StringTable {
+    data: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        StringTable { data: vec![0] }
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let offset = self.data.len() as u32;
+        self.data.extend_from_slice(s.as_bytes());
+        self.data.push(0);
+        offset
+    }
+}
+
+pub struct ElfBuilder {
+    elf_type: u16,
+    entry: u64,
+    sections: Vec<Section>,
+    segments: Vec<Segment>,
+    symbols: Vec<Symbol>,
+}
+
+fn align_to(buf: &mut Vec<u8>, align: u64) {
+    while !(buf.len() as u64).is_multiple_of(align.max(1)) {
+        buf.push(0);
+    }
+}
+
+impl ElfBuilder {
+    pub fn new(elf_type: u16) -> Self {
+        ElfBuilder {
+            elf_type,
+            entry: 0,
+            sections: vec![],
+            segments: vec![],
+            symbols: vec![],
+        }
+    }
+
+    pub fn set_entry(&mut self, entry: u64) {
+        self.entry = entry;
+    }
+
+    /// Returns the section header index of the new section.
+    pub fn add_section(&mut self, section: Section) -> u16 {
+        self.sections.push(section);
+        // index 0 is the null section
+        self.sections.len() as u16
+    }
+
+    pub fn add_symbol(&mut self, symbol: Symbol) {
+        self.symbols.push(symbol);
+    }
+
+    pub fn add_segment(&mut self, segment: Segment) {
+        self.segments.push(segment);
+    }
+
+    /// Lays out the file: header, program headers, section contents, then
+    /// the section header table. `.symtab`, `.strtab` and `.shstrtab` are
+    /// appended automatically.
+    pub fn build(mut self) -> Vec<u8> {
+        let mut strtab = StringTable::new();
+        let mut symtab = vec![0; SYM_SIZE];
+        for sym in &self.symbols {
+            symtab.extend_from_slice(&strtab.add(&sym.name).to_le_bytes());
+            symtab.push((STB_GLOBAL << 4) | sym.kind);
+            symtab.push(0);
+            symtab.extend_from_slice(&sym.section.to_le_bytes());
+            symtab.extend_from_slice(&sym.value.to_le_bytes());
+            symtab.extend_from_slice(&sym.size.to_le_bytes());
+        }
+        let strtab_index = self.sections.len() as u32 + 2;
+        let mut symtab_section = Section::progbits(".symtab", 0, 0, symtab);
+        symtab_section.kind = SHT_SYMTAB;
+        symtab_section.align = 8;
+        self.sections.push(symtab_section);
+        let mut strtab_section = Section::progbits(".strtab", 0, 0, strtab.data);
+        strtab_section.kind = SHT_STRTAB;
+        self.sections.push(strtab_section);
+
+        let mut shstrtab = StringTable::new();
+        let names: Vec<u32> = self
+            .sections
+            .iter()
+            .map(|x| shstrtab.add(&x.name))
+            .collect();
+        let shstrtab_name = shstrtab.add(".shstrtab");
+        let mut shstrtab_section = Section::progbits(".shstrtab", 0, 0, shstrtab.data);
+        shstrtab_section.kind = SHT_STRTAB;
+        self.sections.push(shstrtab_section);
+
+        let phnum = self.segments.len();
+        let mut out = vec![0; EHDR_SIZE + phnum * PHDR_SIZE];
+        let mut offsets = vec![];
+        for section in &self.sections {
+            align_to(&mut out, section.align);
+            offsets.push(out.len() as u64);
+            out.extend_from_slice(&section.data);
+        }
+
+        let mut program_headers = vec![];
+        for segment in &self.segments {
+            let index = segment.section as usize - 1;
+            let section = &self.sections[index];
+            let (offset, file_size) = if section.kind == SHT_NOBITS {
+                // nothing to map from the file, any offset that fits the
+                // address will do
+                (section.addr % PAGE_SIZE, 0)
+            } else {
+                (offsets[index], section.size())
+            };
+            program_headers.extend_from_slice(&PT_LOAD.to_le_bytes());
+            program_headers.extend_from_slice(&segment.flags.to_le_bytes());
+            program_headers.extend_from_slice(&offset.to_le_bytes());
+            // vaddr, paddr
+            program_headers.extend_from_slice(&section.addr.to_le_bytes());
+            program_headers.extend_from_slice(&section.addr.to_le_bytes());
+            program_headers.extend_from_slice(&file_size.to_le_bytes());
+            program_headers.extend_from_slice(&section.size().to_le_bytes());
+            program_headers.extend_from_slice(&PAGE_SIZE.to_le_bytes());
+        }
+        out[EHDR_SIZE..EHDR_SIZE + phnum * PHDR_SIZE].copy_from_slice(&program_headers);
+        align_to(&mut out, 8);
+        let shoff = out.len() as u64;
+
+        // null section header
+        out.extend_from_slice(&[0; SHDR_SIZE]);
+        for (i, section) in self.sections.iter().enumerate() {
+            let name = names.get(i).copied().unwrap_or(shstrtab_name);
+            let (link, info, entsize) = if section.kind == SHT_SYMTAB {
+                // info: index of the first non-local symbol
+                (strtab_index, 1u32, SYM_SIZE as u64)
+            } else {
+                (0, 0, 0)
+            };
+            out.extend_from_slice(&name.to_le_bytes());
+            out.extend_from_slice(&section.kind.to_le_bytes());
+            out.extend_from_slice(&section.flags.to_le_bytes());
+            out.extend_from_slice(&section.addr.to_le_bytes());
+            out.extend_from_slice(&offsets[i].to_le_bytes());
+            out.extend_from_slice(&section.size().to_le_bytes());
+            out.extend_from_slice(&link.to_le_bytes());
+            out.extend_from_slice(&info.to_le_bytes());
+            out.extend_from_slice(&section.align.to_le_bytes());
+            out.extend_from_slice(&entsize.to_le_bytes());
+        }
+
+        let shnum = self.sections.len() as u16 + 1;
+        let mut header = vec![];
+        header.extend_from_slice(b"\x7fELF");
+        // 64 bit, little endian, current version, System V ABI
+        header.extend_from_slice(&[2, 1, 1, 0]);
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&self.elf_type.to_le_bytes());
+        header.extend_from_slice(&EM_X86_64.to_le_bytes());
+        header.extend_from_slice(&1u32.to_le_bytes());
+        header.extend_from_slice(&self.entry.to_le_bytes());
+        let phoff = if phnum > 0 { EHDR_SIZE as u64 } else { 0 };
+        header.extend_from_slice(&phoff.to_le_bytes());
+        header.extend_from_slice(&shoff.to_le_bytes());
+        // flags
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&(phnum as u16).to_le_bytes());
+        header.extend_from_slice(&(SHDR_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&shnum.to_le_bytes());
+        // the section name table is always last
+        header.extend_from_slice(&(shnum - 1).to_le_bytes());
+        out[..EHDR_SIZE].copy_from_slice(&header);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ElfBuilder, Section, Segment, Symbol, ET_EXEC, PAGE_SIZE, PF_R, PF_W, PF_X, SHF_ALLOC,
+        SHF_EXECINSTR, SHF_WRITE, STT_FUNC,
+    };
+
+    fn u16_at(buf: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(buf: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn section_headers() {
+        let mut elf = ElfBuilder::new(ET_EXEC);
+        let text = elf.add_section(Section::nobits(
+            ".text",
+            SHF_ALLOC | SHF_EXECINSTR,
+            0x1000,
+            0x20,
+        ));
+        elf.add_section(Section::progbits(".comment", 0, 0, b"bf".to_vec()));
+        elf.add_symbol(Symbol {
+            name: "bf_program".to_owned(),
+            value: 0x1000,
+            size: 0x20,
+            section: text,
+            kind: STT_FUNC,
+        });
+        let buf = elf.build();
+
+        assert_eq!(&buf[..4], b"\x7fELF");
+        assert_eq!(u16_at(&buf, 16), ET_EXEC);
+        // null, .text, .comment, .symtab, .strtab, .shstrtab
+        let shnum = u16_at(&buf, 60) as usize;
+        assert_eq!(shnum, 6);
+        let shoff = u64_at(&buf, 40) as usize;
+        assert_eq!(buf.len(), shoff + shnum * 64);
+
+        let text_header = shoff + 64 * text as usize;
+        assert_eq!(u64_at(&buf, text_header + 16), 0x1000);
+        assert_eq!(u64_at(&buf, text_header + 32), 0x20);
+
+        let shstrndx = u16_at(&buf, 62) as usize;
+        let shstrtab_header = shoff + 64 * shstrndx;
+        let shstrtab_offset = u64_at(&buf, shstrtab_header + 24) as usize;
+        let shstrtab_size = u64_at(&buf, shstrtab_header + 32) as usize;
+        let names = &buf[shstrtab_offset..shstrtab_offset + shstrtab_size];
+        assert_eq!(
+            names,
+            b"\0.text\0.comment\0.symtab\0.strtab\0.shstrtab\0".as_slice()
+        );
+
+        // the symbol right after the null one
+        let symtab_header = shoff + 64 * 3;
+        let symtab_offset = u64_at(&buf, symtab_header + 24) as usize;
+        let sym = symtab_offset + 24;
+        assert_eq!(u16_at(&buf, sym + 6), text);
+        assert_eq!(u64_at(&buf, sym + 8), 0x1000);
+        assert_eq!(u64_at(&buf, sym + 16), 0x20);
+    }
+
+    #[test]
+    fn program_headers() {
+        let mut elf = ElfBuilder::new(ET_EXEC);
+        let mut text =
+            Section::progbits(".text", SHF_ALLOC | SHF_EXECINSTR, 0x401000, vec![0xc3; 3]);
+        text.align = PAGE_SIZE;
+        let text = elf.add_section(text);
+        let bss = elf.add_section(Section::nobits(
+            ".bss",
+            SHF_ALLOC | SHF_WRITE,
+            0x600000,
+            100,
+        ));
+        elf.add_segment(Segment {
+            section: text,
+            flags: PF_R | PF_X,
+        });
+        elf.add_segment(Segment {
+            section: bss,
+            flags: PF_R | PF_W,
+        });
+        elf.set_entry(0x401000);
+        let buf = elf.build();
+
+        assert_eq!(u64_at(&buf, 24), 0x401000);
+        let phoff = u64_at(&buf, 32) as usize;
+        assert_eq!(phoff, 64);
+        assert_eq!(u16_at(&buf, 54), 56);
+        assert_eq!(u16_at(&buf, 56), 2);
+
+        // type, flags, offset, vaddr, paddr, filesz, memsz, align
+        let text_header = phoff;
+        assert_eq!(u32_at(&buf, text_header + 4), PF_R | PF_X);
+        let offset = u64_at(&buf, text_header + 8) as usize;
+        assert_eq!(offset % PAGE_SIZE as usize, 0);
+        assert_eq!(&buf[offset..offset + 3], [0xc3; 3]);
+        assert_eq!(u64_at(&buf, text_header + 16), 0x401000);
+        assert_eq!(u64_at(&buf, text_header + 32), 3);
+        assert_eq!(u64_at(&buf, text_header + 40), 3);
+
+        let bss_header = phoff + 56;
+        assert_eq!(u32_at(&buf, bss_header), 1);
+        assert_eq!(u64_at(&buf, bss_header + 16), 0x600000);
+        assert_eq!(u64_at(&buf, bss_header + 32), 0);
+        assert_eq!(u64_at(&buf, bss_header + 40), 100);
+    }
+}
@@ -0,0 +1,410 @@
+//! Registration of JIT code with GDB's JIT compilation interface.
+//!
+//! GDB puts a breakpoint on `__jit_debug_register_code` and, whenever it is
+//! hit, reads the in-memory object file referenced by
+//! `__jit_debug_descriptor.relevant_entry`. The object file built here has the
+//! loop symbols from `LoopRegions`, a DWARF line table mapping every emitted
+//! instruction back to the `.bf` source and a frame description so that gdb
+//! can unwind out of the generated code.
+//! See https://sourceware.org/gdb/onlinedocs/gdb/JIT-Interface.html
+
+use std::{
+    ptr::{addr_of_mut, null, null_mut},
+    sync::Mutex,
+};
+
+use crate::{
+    elf::{ElfBuilder, Section, Symbol, ET_EXEC, SHF_ALLOC, SHF_EXECINSTR, STT_FUNC},
+    jit_utils::CodeRegion,
+    parser::SourceLoc,
+};
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+#[no_mangle]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: null_mut(),
+    first_entry: null_mut(),
+};
+
+/// GDB sets a breakpoint here, so it must not be inlined or optimized out.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    unsafe { std::arch::asm!("") };
+}
+
+// Guards the linked list in `__jit_debug_descriptor`
+static REGISTRATION_LOCK: Mutex<()> = Mutex::new(());
+
+/// Keeps the code registered with gdb until dropped.
+pub struct GdbRegistration {
+    entry: Box<JitCodeEntry>,
+    _symfile: Vec<u8>,
+}
+
+impl GdbRegistration {
+    pub fn register(symfile: Vec<u8>) -> Self {
+        let mut entry = Box::new(JitCodeEntry {
+            next_entry: null_mut(),
+            prev_entry: null_mut(),
+            symfile_addr: symfile.as_ptr(),
+            symfile_size: symfile.len() as u64,
+        });
+        let _guard = REGISTRATION_LOCK.lock().unwrap();
+        unsafe {
+            let descriptor = addr_of_mut!(__jit_debug_descriptor);
+            entry.next_entry = (*descriptor).first_entry;
+            if !entry.next_entry.is_null() {
+                (*entry.next_entry).prev_entry = &mut *entry;
+            }
+            (*descriptor).first_entry = &mut *entry;
+            (*descriptor).relevant_entry = &mut *entry;
+            (*descriptor).action_flag = JIT_REGISTER_FN;
+            __jit_debug_register_code();
+        }
+        GdbRegistration {
+            entry,
+            _symfile: symfile,
+        }
+    }
+}
+
+impl Drop for GdbRegistration {
+    fn drop(&mut self) {
+        let _guard = REGISTRATION_LOCK.lock().unwrap();
+        unsafe {
+            let descriptor = addr_of_mut!(__jit_debug_descriptor);
+            let entry = &mut *self.entry;
+            if entry.prev_entry.is_null() {
+                (*descriptor).first_entry = entry.next_entry;
+            } else {
+                (*entry.prev_entry).next_entry = entry.next_entry;
+            }
+            if !entry.next_entry.is_null() {
+                (*entry.next_entry).prev_entry = entry.prev_entry;
+            }
+            (*descriptor).relevant_entry = entry;
+            (*descriptor).action_flag = JIT_UNREGISTER_FN;
+            __jit_debug_register_code();
+            (*descriptor).relevant_entry = null_mut();
+            (*descriptor).action_flag = JIT_NOACTION;
+            entry.symfile_addr = null();
+        }
+    }
+}
+
+fn uleb128(out: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb128(out: &mut Vec<u8>, mut v: i64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        let done = (v == 0 && byte & 0x40 == 0) || (v == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+// DWARF 4 line number program, using only standard opcodes.
+fn debug_line(source_name: &str, base: u64, size: u64, rows: &[(usize, SourceLoc)]) -> Vec<u8> {
+    const DW_LNS_COPY: u8 = 1;
+    const DW_LNS_ADVANCE_PC: u8 = 2;
+    const DW_LNS_ADVANCE_LINE: u8 = 3;
+    const DW_LNS_SET_COLUMN: u8 = 5;
+    const DW_LNE_END_SEQUENCE: u8 = 1;
+    const DW_LNE_SET_ADDRESS: u8 = 2;
+
+    let mut header = vec![
+        1,            // minimum_instruction_length
+        1,            // maximum_operations_per_instruction
+        1,            // default_is_stmt
+        (-5i8) as u8, // line_base
+        14,           // line_range
+        13,           // opcode_base
+    ];
+    header.extend_from_slice(&[0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+    // no include directories
+    header.push(0);
+    header.extend_from_slice(source_name.as_bytes());
+    // name terminator, directory, mtime, length, end of file names
+    header.extend_from_slice(&[0, 0, 0, 0, 0]);
+
+    let mut program = vec![0, 9, DW_LNE_SET_ADDRESS];
+    program.extend_from_slice(&base.to_le_bytes());
+    let (mut offset, mut line) = (0, 1);
+    for (row_offset, loc) in rows {
+        uleb128_op(
+            &mut program,
+            DW_LNS_ADVANCE_PC,
+            (*row_offset - offset) as u64,
+        );
+        program.push(DW_LNS_ADVANCE_LINE);
+        sleb128(&mut program, loc.line as i64 - line as i64);
+        uleb128_op(&mut program, DW_LNS_SET_COLUMN, loc.col as u64);
+        program.push(DW_LNS_COPY);
+        offset = *row_offset;
+        line = loc.line;
+    }
+    uleb128_op(&mut program, DW_LNS_ADVANCE_PC, size - offset as u64);
+    program.extend_from_slice(&[0, 1, DW_LNE_END_SEQUENCE]);
+
+    let mut out = vec![];
+    let unit_length = 2 + 4 + header.len() + program.len();
+    out.extend_from_slice(&(unit_length as u32).to_le_bytes());
+    out.extend_from_slice(&4u16.to_le_bytes());
+    out.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    out.extend_from_slice(&header);
+    out.extend_from_slice(&program);
+    out
+}
+
+fn uleb128_op(out: &mut Vec<u8>, opcode: u8, v: u64) {
+    out.push(opcode);
+    uleb128(out, v);
+}
+
+// A single compile unit covering the code and pointing at the line table.
+fn debug_abbrev_and_info(source_name: &str, base: u64, size: u64) -> (Vec<u8>, Vec<u8>) {
+    const DW_TAG_COMPILE_UNIT: u8 = 0x11;
+    const DW_AT_NAME: u8 = 0x03;
+    const DW_AT_STMT_LIST: u8 = 0x10;
+    const DW_AT_LOW_PC: u8 = 0x11;
+    const DW_AT_HIGH_PC: u8 = 0x12;
+    const DW_FORM_ADDR: u8 = 0x01;
+    const DW_FORM_DATA8: u8 = 0x07;
+    const DW_FORM_STRING: u8 = 0x08;
+    const DW_FORM_SEC_OFFSET: u8 = 0x17;
+
+    let abbrev = vec![
+        1,
+        DW_TAG_COMPILE_UNIT,
+        0, // no children
+        DW_AT_NAME,
+        DW_FORM_STRING,
+        DW_AT_STMT_LIST,
+        DW_FORM_SEC_OFFSET,
+        DW_AT_LOW_PC,
+        DW_FORM_ADDR,
+        DW_AT_HIGH_PC,
+        DW_FORM_DATA8,
+        0,
+        0,
+        0,
+    ];
+
+    let mut die = vec![1];
+    die.extend_from_slice(source_name.as_bytes());
+    die.push(0);
+    die.extend_from_slice(&0u32.to_le_bytes());
+    die.extend_from_slice(&base.to_le_bytes());
+    die.extend_from_slice(&size.to_le_bytes());
+
+    let mut info = vec![];
+    // version, abbrev offset, address size
+    let unit_length = 2 + 4 + 1 + die.len();
+    info.extend_from_slice(&(unit_length as u32).to_le_bytes());
+    info.extend_from_slice(&4u16.to_le_bytes());
+    info.extend_from_slice(&0u32.to_le_bytes());
+    info.push(8);
+    info.extend_from_slice(&die);
+    (abbrev, info)
+}
+
+// The generated code never touches the stack, so the CFA is always %rsp + 8
+// with the return address right below it.
+fn debug_frame(base: u64, size: u64) -> Vec<u8> {
+    const DW_CFA_DEF_CFA: u8 = 0x0c;
+    const DW_CFA_OFFSET: u8 = 0x80;
+    const RSP: u8 = 7;
+    const RIP: u8 = 16;
+
+    let mut cie = vec![];
+    cie.extend_from_slice(&0xffffffffu32.to_le_bytes());
+    cie.push(1); // version
+    cie.push(0); // empty augmentation
+    uleb128(&mut cie, 1); // code alignment
+    sleb128(&mut cie, -8); // data alignment
+    cie.push(RIP);
+    cie.extend_from_slice(&[DW_CFA_DEF_CFA, RSP, 8]);
+    cie.extend_from_slice(&[DW_CFA_OFFSET | RIP, 1]);
+    while (cie.len() + 4) % 8 != 0 {
+        cie.push(0); // DW_CFA_nop
+    }
+
+    let mut out = vec![];
+    out.extend_from_slice(&(cie.len() as u32).to_le_bytes());
+    out.extend_from_slice(&cie);
+
+    let mut fde = vec![];
+    fde.extend_from_slice(&0u32.to_le_bytes()); // offset of the CIE
+    fde.extend_from_slice(&base.to_le_bytes());
+    fde.extend_from_slice(&size.to_le_bytes());
+    while (fde.len() + 4) % 8 != 0 {
+        fde.push(0);
+    }
+    out.extend_from_slice(&(fde.len() as u32).to_le_bytes());
+    out.extend_from_slice(&fde);
+    out
+}
+
+/// Builds the object file describing `size` bytes of code loaded at `base`.
+///
+/// `regions` become function symbols and `rows` (code offset, source location
+/// of the instruction starting there) become the line table.
+pub fn symbol_file(
+    source_name: &str,
+    base: usize,
+    size: usize,
+    regions: &[CodeRegion],
+    rows: &[(usize, SourceLoc)],
+) -> Vec<u8> {
+    let (base, size) = (base as u64, size as u64);
+    let mut elf = ElfBuilder::new(ET_EXEC);
+    let text = elf.add_section(Section::nobits(
+        ".text",
+        SHF_ALLOC | SHF_EXECINSTR,
+        base,
+        size,
+    ));
+    let (abbrev, info) = debug_abbrev_and_info(source_name, base, size);
+    elf.add_section(Section::progbits(".debug_abbrev", 0, 0, abbrev));
+    elf.add_section(Section::progbits(".debug_info", 0, 0, info));
+    elf.add_section(Section::progbits(
+        ".debug_line",
+        0,
+        0,
+        debug_line(source_name, base, size, rows),
+    ));
+    elf.add_section(Section::progbits(
+        ".debug_frame",
+        0,
+        0,
+        debug_frame(base, size),
+    ));
+    for region in regions.iter().filter(|x| x.end > x.start) {
+        elf.add_symbol(Symbol {
+            name: region.name.clone(),
+            value: base + region.start as u64,
+            size: (region.end - region.start) as u64,
+            section: text,
+            kind: STT_FUNC,
+        });
+    }
+    elf.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::addr_of;
+
+    use crate::parser::SourceLoc;
+
+    use super::{
+        __jit_debug_descriptor, debug_line, sleb128, uleb128, GdbRegistration, REGISTRATION_LOCK,
+    };
+
+    #[test]
+    fn leb128() {
+        let mut out = vec![];
+        uleb128(&mut out, 624485);
+        assert_eq!(out, vec![0xe5, 0x8e, 0x26]);
+        out.clear();
+        sleb128(&mut out, -123456);
+        assert_eq!(out, vec![0xc0, 0xbb, 0x78]);
+        out.clear();
+        sleb128(&mut out, 63);
+        sleb128(&mut out, 64);
+        assert_eq!(out, vec![0x3f, 0xc0, 0x00]);
+    }
+
+    #[test]
+    fn line_program() {
+        let rows = [
+            (0, SourceLoc { line: 1, col: 1 }),
+            (3, SourceLoc { line: 3, col: 2 }),
+        ];
+        let out = debug_line("a.bf", 0x1000, 8, &rows);
+        let unit_length = u32::from_le_bytes(out[..4].try_into().unwrap()) as usize;
+        assert_eq!(unit_length + 4, out.len());
+        let program_start = 10 + u32::from_le_bytes(out[6..10].try_into().unwrap()) as usize;
+        assert_eq!(
+            &out[program_start..],
+            &[
+                0, 9, 2, 0x00, 0x10, 0, 0, 0, 0, 0, 0, // set_address 0x1000
+                2, 0, 3, 0, 5, 1, 1, // row at 1:1
+                2, 3, 3, 2, 5, 2, 1, // row at 3:2
+                2, 5, 0, 1, 1, // end_sequence at 0x1008
+            ]
+        );
+    }
+
+    #[test]
+    fn registration_list() {
+        let first = GdbRegistration::register(vec![1, 2, 3]);
+        let second = GdbRegistration::register(vec![4]);
+        {
+            let _guard = REGISTRATION_LOCK.lock().unwrap();
+            let descriptor = unsafe { &*addr_of!(__jit_debug_descriptor) };
+            assert_eq!(
+                descriptor.first_entry as *const _,
+                &*second.entry as *const _
+            );
+            assert_eq!(
+                second.entry.next_entry as *const _,
+                &*first.entry as *const _
+            );
+            assert_eq!(
+                first.entry.prev_entry as *const _,
+                &*second.entry as *const _
+            );
+            assert_eq!(first.entry.symfile_size, 3);
+        }
+        drop(second);
+        {
+            let _guard = REGISTRATION_LOCK.lock().unwrap();
+            let descriptor = unsafe { &*addr_of!(__jit_debug_descriptor) };
+            assert_eq!(
+                descriptor.first_entry as *const _,
+                &*first.entry as *const _
+            );
+            assert!(first.entry.prev_entry.is_null());
+        }
+        drop(first);
+        let descriptor = unsafe { &*addr_of!(__jit_debug_descriptor) };
+        assert!(descriptor.first_entry.is_null());
+    }
+}
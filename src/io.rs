@@ -0,0 +1,128 @@
+use std::io::{BufWriter, ErrorKind, Read, Write};
+
+/// What `,` stores when the input is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EofPolicy {
+    Zero,
+    /// 255, which is what storing C's `EOF` (-1) in a byte gives.
+    AllOnes,
+    /// Leave the cell as it was.
+    #[default]
+    Unchanged,
+}
+
+impl EofPolicy {
+    /// The value a cell holding `current` gets on end of input.
+    pub fn on_eof(self, current: u8) -> u8 {
+        match self {
+            EofPolicy::Zero => 0,
+            EofPolicy::AllOnes => 255,
+            EofPolicy::Unchanged => current,
+        }
+    }
+}
+
+/// Input and buffered output of a running program.
+///
+/// The compiled backends call into it through `bf_read`/`bf_write`, which
+/// can't return errors, so the first error is kept and reported by `finish`.
+pub struct Io<R: Read, W: Write> {
+    input: R,
+    output: BufWriter<W>,
+    eof_policy: EofPolicy,
+    error: Option<std::io::Error>,
+}
+
+impl<R: Read, W: Write> Io<R, W> {
+    pub fn new(input: R, output: W, eof_policy: EofPolicy) -> Self {
+        Io {
+            input,
+            output: BufWriter::new(output),
+            eof_policy,
+            error: None,
+        }
+    }
+
+    /// Reads one byte for a cell holding `current`.
+    pub fn read(&mut self, current: u8) -> u8 {
+        // Whatever the program printed so far may be the prompt for this.
+        if let Err(e) = self.output.flush() {
+            self.error.get_or_insert(e);
+        }
+        let mut buf = [0];
+        loop {
+            match self.input.read(&mut buf) {
+                Ok(0) => return self.eof_policy.on_eof(current),
+                Ok(_) => return buf[0],
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.error.get_or_insert(e);
+                    return self.eof_policy.on_eof(current);
+                }
+            }
+        }
+    }
+
+    pub fn write(&mut self, c: u8) {
+        if let Err(e) = self.output.write_all(&[c]) {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    /// Flushes the output, and returns the first error reading or writing hit.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        let flushed = self.output.flush();
+        match self.error {
+            Some(e) => Err(e),
+            None => flushed,
+        }
+    }
+}
+
+/// `void bf_write(io, c)` for generated code, `io` being an `Io<R, W>`.
+///
+/// # Safety
+///
+/// `io` must point to a live `Io<R, W>` not borrowed anywhere else.
+pub unsafe extern "C" fn bf_write<R: Read, W: Write>(io: *mut Io<R, W>, c: u8) {
+    (*io).write(c);
+}
+
+/// `u8 bf_read(io, current)` for generated code, `io` being an `Io<R, W>`.
+///
+/// # Safety
+///
+/// `io` must point to a live `Io<R, W>` not borrowed anywhere else.
+pub unsafe extern "C" fn bf_read<R: Read, W: Write>(io: *mut Io<R, W>, current: u8) -> u8 {
+    (*io).read(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EofPolicy, Io};
+
+    #[test]
+    fn eof_policies() {
+        let mut out = vec![];
+        let mut io = Io::new(b"a".as_slice(), &mut out, EofPolicy::Zero);
+        assert_eq!(io.read(7), b'a');
+        assert_eq!(io.read(7), 0);
+        io.finish().unwrap();
+
+        let mut io = Io::new(b"".as_slice(), std::io::sink(), EofPolicy::AllOnes);
+        assert_eq!(io.read(7), 255);
+        let mut io = Io::new(b"".as_slice(), std::io::sink(), EofPolicy::Unchanged);
+        assert_eq!(io.read(7), 7);
+    }
+
+    #[test]
+    fn buffered_output() {
+        let mut out = vec![];
+        let mut io = Io::new(b"x".as_slice(), &mut out, EofPolicy::default());
+        io.write(b'>');
+        io.read(0);
+        io.write(b'!');
+        io.finish().unwrap();
+        assert_eq!(out, b">!");
+    }
+}
@@ -1,10 +1,17 @@
-use std::{ffi::c_void, fmt::format, ptr::null_mut};
+use std::{
+    ffi::c_void,
+    fmt::format,
+    ptr::null_mut,
+    time::{Duration, Instant},
+};
 
 use nix::{
     libc::{memcpy, munmap},
     sys::mman::{mprotect, MapFlags, ProtFlags},
 };
 
+use crate::{code_cache::CodeCache, io::EofPolicy, parser::SourceLoc};
+
 fn alloc_rw_mem(sz: usize) -> *mut c_void {
     unsafe {
         let addr: *mut c_void = null_mut();
@@ -111,6 +118,207 @@ impl CodeEmitter {
     }
 }
 
+/// Optional extras the JITs can produce alongside the generated code.
+#[derive(Debug, Clone, Default)]
+pub struct JitOptions {
+    /// Append symbols for the generated code to `/tmp/perf-<pid>.map`.
+    pub perf_map: bool,
+    /// Register the generated code with gdb while it runs.
+    pub gdb: bool,
+    /// Print the annotated disassembly of the generated code instead of
+    /// running it.
+    pub dump_asm: bool,
+    /// Name of the `.bf` file in debug info, `program.bf` if not set.
+    pub source_name: Option<String>,
+    /// LLVM optimization level, 0 to 3, 3 if not set.
+    pub opt_level: Option<u32>,
+    /// LLVM pass pipeline in `opt -passes` syntax, `default<O{opt_level}>` if
+    /// not set. An empty pipeline runs no IR passes.
+    pub passes: Option<String>,
+    /// Print how long each LLVM or Cranelift compilation stage took to
+    /// stderr.
+    pub time_stages: bool,
+    /// Attach DWARF line info for the `.bf` source to LLVM-generated code.
+    pub debug_info: bool,
+    /// What `,` stores at the end of input in LLVM-generated code, C, wasm
+    /// and assembly.
+    pub eof_policy: EofPolicy,
+    /// Reuse LLVM object code compiled by earlier runs from this cache, and
+    /// add newly compiled code to it.
+    pub code_cache: Option<CodeCache>,
+}
+
+impl JitOptions {
+    pub fn source_name(&self) -> &str {
+        self.source_name.as_deref().unwrap_or("program.bf")
+    }
+
+    pub fn opt_level(&self) -> u32 {
+        self.opt_level.unwrap_or(3)
+    }
+
+    pub fn pass_pipeline(&self) -> String {
+        self.passes
+            .clone()
+            .unwrap_or_else(|| format!("default<O{}>", self.opt_level()))
+    }
+}
+
+pub const STATUS_OK: i32 = 0;
+/// The data pointer moved off the tape; `JitResult::dataptr` is where to.
+pub const STATUS_OUT_OF_BOUNDS: i32 = 1;
+
+/// Return value of the programs the LLVM and Cranelift JITs compile.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JitResult {
+    pub dataptr: i64,
+    pub status: i32,
+}
+
+/// Wall-clock time of each compilation stage, and the size of the code
+/// when the backend knows it, reported on stderr with
+/// `JitOptions::time_stages`.
+pub struct StageTimings {
+    enabled: bool,
+    stages: Vec<(&'static str, Duration)>,
+    code_size: Option<usize>,
+}
+
+impl StageTimings {
+    pub fn new(enabled: bool) -> Self {
+        StageTimings {
+            enabled,
+            stages: vec![],
+            code_size: None,
+        }
+    }
+
+    /// Whether they get reported, which backends also take as a hint to
+    /// measure things that cost extra, like the code size.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn stages(&self) -> &[(&'static str, Duration)] {
+        &self.stages
+    }
+
+    pub fn code_size(&self) -> Option<usize> {
+        self.code_size
+    }
+
+    pub fn set_code_size(&mut self, size: usize) {
+        self.code_size = Some(size);
+    }
+
+    pub fn time<T>(&mut self, stage: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(stage, start.elapsed());
+        result
+    }
+
+    /// Adds a stage timed by the caller, for ones that don't fit in a closure.
+    pub fn record(&mut self, stage: &'static str, time: Duration) {
+        self.stages.push((stage, time));
+    }
+
+    pub fn report(&self) {
+        if !self.enabled {
+            return;
+        }
+        for (stage, time) in &self.stages {
+            eprintln!("{:<10} {:>10.3} ms", stage, time.as_secs_f64() * 1000.0);
+        }
+        if let Some(size) = self.code_size {
+            eprintln!("{:<10} {:>10} bytes", "code size", size);
+        }
+    }
+}
+
+/// A named range of generated code, as offsets into the code buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeRegion {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Name of the region for code outside of every loop.
+pub const PROGRAM_REGION: &str = "bf_program";
+
+/// Name of the region for the loop from `open` to `close`.
+pub fn loop_region_name(open: SourceLoc, close: SourceLoc) -> String {
+    format!(
+        "bf_loop@{}:{}-{}:{}",
+        open.line, open.col, close.line, close.col
+    )
+}
+
+/// Splits generated code into non-overlapping regions, each named after the
+/// innermost loop it belongs to. Code outside of every loop is `bf_program`.
+///
+/// The code generators call `enter_loop`/`leave_loop` with the current code
+/// offset around every `[...]` they emit.
+pub struct LoopRegions {
+    // (source location of '[', source location of ']')
+    loops: Vec<(SourceLoc, SourceLoc)>,
+    stack: Vec<usize>,
+    // (index into loops, start offset, end offset)
+    regions: Vec<(Option<usize>, usize, usize)>,
+    region_start: usize,
+}
+
+impl LoopRegions {
+    pub fn new(start_offset: usize) -> Self {
+        LoopRegions {
+            loops: vec![],
+            stack: vec![],
+            regions: vec![],
+            region_start: start_offset,
+        }
+    }
+
+    fn close_region(&mut self, offset: usize) {
+        if offset > self.region_start {
+            self.regions
+                .push((self.stack.last().copied(), self.region_start, offset));
+        }
+        self.region_start = offset;
+    }
+
+    pub fn enter_loop(&mut self, offset: usize, open: SourceLoc) {
+        self.close_region(offset);
+        self.loops.push((open, open));
+        self.stack.push(self.loops.len() - 1);
+    }
+
+    pub fn leave_loop(&mut self, offset: usize, close: SourceLoc) {
+        self.close_region(offset);
+        let id = self.stack.pop().expect("leave_loop without enter_loop");
+        self.loops[id].1 = close;
+    }
+
+    pub fn finish(mut self, end_offset: usize) -> Vec<CodeRegion> {
+        self.close_region(end_offset);
+        self.regions
+            .iter()
+            .map(|(id, start, end)| CodeRegion {
+                name: match id {
+                    Some(id) => {
+                        let (open, close) = self.loops[*id];
+                        loop_region_name(open, close)
+                    }
+                    None => String::from(PROGRAM_REGION),
+                },
+                start: *start,
+                end: *end,
+            })
+            .collect()
+    }
+}
+
 pub fn compute_relative_32bit_offset(jump_from: usize, jump_to: usize) -> u32 {
     if jump_to >= jump_from {
         let diff = jump_to - jump_from;
@@ -131,9 +339,9 @@ pub fn compute_relative_32bit_offset(jump_from: usize, jump_to: usize) -> u32 {
 mod tests {
     use std::mem::transmute_copy;
 
-    use crate::jit_utils::compute_relative_32bit_offset;
+    use crate::{jit_utils::compute_relative_32bit_offset, parser::SourceLoc};
 
-    use super::{CodeEmitter, JitProgram};
+    use super::{CodeEmitter, CodeRegion, JitProgram, LoopRegions};
 
     #[test]
     fn compute_relative_offset() {
@@ -206,6 +414,36 @@ mod tests {
         assert!(em3.code()[15] == 0xF2);
     }
 
+    #[test]
+    fn test_loop_regions() {
+        let loc = |line, col| SourceLoc { line, col };
+        let mut regions = LoopRegions::new(0);
+        regions.enter_loop(10, loc(1, 3));
+        regions.enter_loop(20, loc(2, 1));
+        regions.leave_loop(30, loc(2, 5));
+        regions.leave_loop(40, loc(3, 1));
+        // empty loop body at the very end
+        regions.enter_loop(50, loc(4, 1));
+        regions.leave_loop(50, loc(4, 2));
+
+        let region = |name: &str, start, end| CodeRegion {
+            name: name.to_owned(),
+            start,
+            end,
+        };
+        assert_eq!(
+            regions.finish(60),
+            vec![
+                region("bf_program", 0, 10),
+                region("bf_loop@1:3-3:1", 10, 20),
+                region("bf_loop@2:1-2:5", 20, 30),
+                region("bf_loop@1:3-3:1", 30, 40),
+                region("bf_program", 40, 50),
+                region("bf_program", 50, 60),
+            ]
+        );
+    }
+
     #[test]
     fn test_jit() {
         let code: Vec<u8> = vec![
@@ -1,11 +1,32 @@
 const MEMORY_SIZE: usize = 30000;
+pub mod asm_backend;
+pub mod bench;
 pub mod bf;
+#[cfg(feature = "llvm")]
+pub mod build;
 pub mod bytecode_bf;
+pub mod c_backend;
+pub mod code_cache;
+pub mod compact;
+pub mod coverage;
+#[cfg(feature = "cranelift")]
+pub mod cranelift_jit;
+pub mod disasm;
+pub mod elf;
+pub mod gdb_jit;
+pub mod io;
 pub mod jit_utils;
+#[cfg(feature = "llvm")]
 pub mod llvm_jit;
+pub mod observer;
 pub mod optbytecode_jit;
 pub mod parser;
+pub mod perf_map;
+pub mod profiler;
 pub mod simple_jit;
+pub mod threaded;
+pub mod tiered;
+pub mod wasm_backend;
 
 #[cfg(test)]
 mod tests {
@@ -1,34 +1,68 @@
-use crate::bytecode_bf::{ByteCode, Change};
-use crate::{parser::Parser, MEMORY_SIZE};
+use crate::bytecode_bf::{ByteCode, ByteCodeProgram, Change};
+use crate::code_cache::CodeCache;
+use crate::io::{self, EofPolicy, Io};
+use crate::jit_utils::{loop_region_name, CodeRegion, JitOptions, StageTimings, PROGRAM_REGION};
+pub use crate::jit_utils::{JitResult, STATUS_OK, STATUS_OUT_OF_BOUNDS};
+use crate::parser::SourceLoc;
+use crate::{parser::Parser, perf_map, MEMORY_SIZE};
+use inkwell::attributes::{Attribute, AttributeLoc};
 use inkwell::basic_block::BasicBlock;
+use inkwell::builder::Builder;
 use inkwell::context::Context;
-use inkwell::module::Linkage;
-use inkwell::targets::InitializationConfig;
-use inkwell::types::BasicMetadataTypeEnum;
-use inkwell::values::PointerValue;
-use std::alloc::Layout;
-use std::io::Read;
-
-extern "C" fn putchar(c: u32) -> u32 {
-    unsafe {
-        print!("{}", char::from_u32_unchecked(c));
-    }
-    return c;
-}
-extern "C" fn getchar() -> u32 {
-    let mut buf = vec![0];
-    std::io::stdin().read_exact(&mut buf).unwrap();
-    return buf[0] as u32;
-}
+use inkwell::debug_info::{
+    debug_metadata_version, AsDIScope, DIFlags, DIFlagsConstants, DISubprogram, DWARFEmissionKind,
+    DWARFSourceLanguage, DebugInfoBuilder,
+};
+use inkwell::memory_buffer::MemoryBuffer;
+use inkwell::module::{FlagBehavior, Linkage, Module};
+use inkwell::object_file::ObjectFile;
+use inkwell::passes::PassBuilderOptions;
+use inkwell::targets::{
+    CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine,
+};
+use inkwell::values::{FunctionValue, IntValue, PointerValue};
+use inkwell::AddressSpace;
+use inkwell::OptimizationLevel;
+use llvm_sys_160::core::LLVMCreateMemoryBufferWithMemoryRangeCopy;
+use llvm_sys_160::error::{LLVMDisposeErrorMessage, LLVMErrorRef, LLVMGetErrorMessage};
+use llvm_sys_160::orc2::lljit::{
+    LLVMOrcCreateLLJIT, LLVMOrcDisposeLLJIT, LLVMOrcLLJITAddObjectFile,
+    LLVMOrcLLJITGetGlobalPrefix, LLVMOrcLLJITGetMainJITDylib, LLVMOrcLLJITLookup,
+    LLVMOrcLLJITMangleAndIntern, LLVMOrcLLJITRef,
+};
+use llvm_sys_160::orc2::{
+    LLVMJITEvaluatedSymbol, LLVMJITSymbolFlags, LLVMJITSymbolGenericFlags, LLVMOrcAbsoluteSymbols,
+    LLVMOrcCSymbolMapPair, LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess,
+    LLVMOrcDisposeMaterializationUnit, LLVMOrcJITDylibAddGenerator, LLVMOrcJITDylibDefine,
+};
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
 
 pub enum Action {
     Print,
     Execute,
+    /// Compile ahead of time and link a standalone executable at the path.
+    BuildExecutable(PathBuf),
+    EmitBitcode(PathBuf),
+    /// Assembly and object files leave `bf_write`/`bf_read` for whatever
+    /// they get linked with to define.
+    EmitAssembly(PathBuf),
+    EmitObject(PathBuf),
 }
 
+/// `{ i64, i32 } __llvm_jit(i8 *tape, i64 tape_len, i8 *io)`
+///
+/// Runs the program on a zeroed tape owned by the caller and returns a
+/// `JitResult`. `io` is only passed on to the runtime functions below.
 const JIT_FUNC_NAME: &'static str = "__llvm_jit";
-const PUTCHAR: &'static str = "putchar";
-const GETCHAR: &'static str = "getchar";
+/// `void bf_write(i8 *io, i8 c)`, see `io::bf_write`.
+const BF_WRITE: &str = "bf_write";
+/// `i8 bf_read(i8 *io, i8 current)`, see `io::bf_read`.
+const BF_READ: &str = "bf_read";
 #[macro_export]
 macro_rules! load {
     ($builder: expr, $data: expr, $type: expr) => {
@@ -42,6 +76,401 @@ macro_rules! gep {
     };
 }
 
+/// How a module is optimized and compiled, taken from `JitOptions`.
+struct Compilation {
+    opt_level: OptimizationLevel,
+    passes: String,
+    timings: StageTimings,
+}
+
+impl Compilation {
+    fn new(options: &JitOptions) -> Self {
+        let opt_level = match options.opt_level() {
+            0 => OptimizationLevel::None,
+            1 => OptimizationLevel::Less,
+            2 => OptimizationLevel::Default,
+            3 => OptimizationLevel::Aggressive,
+            level => panic!("Invalid optimization level {}", level),
+        };
+        Compilation {
+            opt_level,
+            passes: options.pass_pipeline(),
+            timings: StageTimings::new(options.time_stages),
+        }
+    }
+
+    fn optimize(&mut self, module: &Module, machine: &TargetMachine) {
+        if self.passes.is_empty() {
+            return;
+        }
+        let passes = &self.passes;
+        self.timings
+            .time("optimize", || {
+                module.run_passes(passes, machine, PassBuilderOptions::create())
+            })
+            .unwrap_or_else(|e| panic!("Failed to run passes {:?}: {}", passes, e));
+    }
+
+    /// Target machine for ahead-of-time output; also sets the module's triple
+    /// and data layout to match it.
+    fn aot_target_machine(&self, module: &Module) -> TargetMachine {
+        let machine =
+            LlvmJit::host_target_machine(self.opt_level, RelocMode::PIC, CodeModel::Default)
+                .expect("Failed to create target machine");
+        module.set_triple(&machine.get_triple());
+        module.set_data_layout(&machine.get_target_data().get_data_layout());
+        machine
+    }
+
+    /// The IR of `module` after the pass pipeline, as `Action::Print` prints it.
+    fn optimized_ir(&mut self, module: &Module) -> String {
+        let machine = self.aot_target_machine(module);
+        self.optimize(module, &machine);
+        module.to_string()
+    }
+
+    fn emit_file(&mut self, module: &Module, file_type: FileType, output: &Path) {
+        let machine = self.aot_target_machine(module);
+        self.optimize(module, &machine);
+        self.timings
+            .time("codegen", || {
+                machine.write_to_file(module, file_type, output)
+            })
+            .unwrap_or_else(|e| panic!("Failed to write {}: {}", output.display(), e));
+    }
+}
+
+/// An ORC LLJIT instance holding a single object file.
+///
+/// The JIT runs object code it compiled itself or took from the code cache,
+/// so that the code that runs is the one whose symbol table gives its size.
+struct ObjectJit {
+    jit: LLVMOrcLLJITRef,
+}
+
+impl ObjectJit {
+    /// Loads `object`, called `name` in LLVM's errors, resolving its
+    /// undefined symbols to `runtime`, and failing that to the symbols of
+    /// this process.
+    fn load(object: &[u8], name: &str, runtime: &[(&str, usize)]) -> Result<Self, String> {
+        unsafe {
+            let mut jit = std::ptr::null_mut();
+            Self::check(LLVMOrcCreateLLJIT(&mut jit, std::ptr::null_mut()))?;
+            let loaded = ObjectJit { jit };
+            let dylib = LLVMOrcLLJITGetMainJITDylib(jit);
+
+            let mut process = std::ptr::null_mut();
+            Self::check(LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess(
+                &mut process,
+                LLVMOrcLLJITGetGlobalPrefix(jit),
+                None,
+                std::ptr::null_mut(),
+            ))?;
+            LLVMOrcJITDylibAddGenerator(dylib, process);
+
+            let flags = LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsExported as u8
+                | LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsCallable as u8;
+            let mut symbols = runtime
+                .iter()
+                .map(|&(name, address)| {
+                    let name = CString::new(name).unwrap();
+                    LLVMOrcCSymbolMapPair {
+                        Name: LLVMOrcLLJITMangleAndIntern(jit, name.as_ptr()),
+                        Sym: LLVMJITEvaluatedSymbol {
+                            Address: address as u64,
+                            Flags: LLVMJITSymbolFlags {
+                                GenericFlags: flags,
+                                TargetFlags: 0,
+                            },
+                        },
+                    }
+                })
+                .collect::<Vec<_>>();
+            let unit = LLVMOrcAbsoluteSymbols(symbols.as_mut_ptr(), symbols.len());
+            // The dylib only takes ownership of the unit on success.
+            Self::check(LLVMOrcJITDylibDefine(dylib, unit))
+                .inspect_err(|_| LLVMOrcDisposeMaterializationUnit(unit))?;
+
+            let name = CString::new(name).unwrap();
+            let buffer = LLVMCreateMemoryBufferWithMemoryRangeCopy(
+                object.as_ptr().cast(),
+                object.len(),
+                name.as_ptr(),
+            );
+            Self::check(LLVMOrcLLJITAddObjectFile(jit, dylib, buffer))?;
+            Ok(loaded)
+        }
+    }
+
+    /// Address of `name`, linking the object on the first lookup.
+    fn lookup(&self, name: &str) -> Result<usize, String> {
+        let name = CString::new(name).unwrap();
+        let mut address = 0;
+        unsafe { Self::check(LLVMOrcLLJITLookup(self.jit, &mut address, name.as_ptr()))? };
+        Ok(address as usize)
+    }
+
+    fn check(error: LLVMErrorRef) -> Result<(), String> {
+        if error.is_null() {
+            return Ok(());
+        }
+        unsafe {
+            let message = LLVMGetErrorMessage(error);
+            let text = CStr::from_ptr(message).to_string_lossy().into_owned();
+            LLVMDisposeErrorMessage(message);
+            Err(text)
+        }
+    }
+}
+
+impl Drop for ObjectJit {
+    fn drop(&mut self) {
+        // Panicking here would abort when dropping during a panic.
+        if let Err(e) = Self::check(unsafe { LLVMOrcDisposeLLJIT(self.jit) }) {
+            eprintln!("Failed to dispose of LLJIT: {}", e);
+        }
+    }
+}
+
+/// The parameters of `JIT_FUNC_NAME`, and the block every bounds check
+/// branches to when it fails.
+struct Tape<'ctx> {
+    memory: PointerValue<'ctx>,
+    len: IntValue<'ctx>,
+    io: PointerValue<'ctx>,
+    out_of_bounds: BasicBlock<'ctx>,
+}
+
+impl<'ctx> Tape<'ctx> {
+    /// Continues in a new block if `dataptr` is within the tape. Negative
+    /// values wrap around, so one unsigned comparison covers both ends.
+    fn check_bounds(
+        &self,
+        context: &'ctx Context,
+        builder: &Builder<'ctx>,
+        dataptr: IntValue<'ctx>,
+    ) {
+        let in_bounds =
+            builder.build_int_compare(inkwell::IntPredicate::ULT, dataptr, self.len, "in_bounds");
+        let function = self.out_of_bounds.get_parent().unwrap();
+        let next = context.append_basic_block(function, "in_bounds");
+        builder.build_conditional_branch(in_bounds, next, self.out_of_bounds);
+        builder.position_at_end(next);
+    }
+
+    /// Branches to `next` if `dataptr` is within the tape, and otherwise
+    /// stores it to `dataptr_addr` before leaving, for code that keeps the
+    /// data pointer out of `dataptr_addr` while it runs.
+    fn branch_if_in_bounds(
+        &self,
+        context: &'ctx Context,
+        builder: &Builder<'ctx>,
+        dataptr_addr: PointerValue<'ctx>,
+        dataptr: IntValue<'ctx>,
+        next: BasicBlock<'ctx>,
+    ) {
+        let in_bounds =
+            builder.build_int_compare(inkwell::IntPredicate::ULT, dataptr, self.len, "in_bounds");
+        let function = self.out_of_bounds.get_parent().unwrap();
+        let out_of_bounds = context.append_basic_block(function, "store_out_of_bounds");
+        builder.build_conditional_branch(in_bounds, next, out_of_bounds);
+        builder.position_at_end(out_of_bounds);
+        builder.build_store(dataptr_addr, dataptr);
+        builder.build_unconditional_branch(self.out_of_bounds);
+    }
+}
+
+/// DWARF debug info for `JIT_FUNC_NAME`, so that every instruction of the
+/// generated code maps back to a line and column of the `.bf` file.
+struct DebugInfo<'ctx> {
+    builder: DebugInfoBuilder<'ctx>,
+    subprogram: DISubprogram<'ctx>,
+}
+
+impl<'ctx> DebugInfo<'ctx> {
+    fn new(
+        context: &'ctx Context,
+        module: &Module<'ctx>,
+        function: FunctionValue<'ctx>,
+        source_name: &str,
+        first_line: usize,
+        is_optimized: bool,
+    ) -> Self {
+        let i32_type = context.i32_type();
+        module.add_basic_value_flag(
+            "Debug Info Version",
+            FlagBehavior::Warning,
+            i32_type.const_int(debug_metadata_version() as u64, false),
+        );
+        module.add_basic_value_flag(
+            "Dwarf Version",
+            FlagBehavior::Warning,
+            i32_type.const_int(4, false),
+        );
+
+        // gdb looks the source up relative to the compilation directory, so
+        // make it absolute.
+        let path = std::env::current_dir()
+            .map(|dir| dir.join(source_name))
+            .unwrap_or_else(|_| PathBuf::from(source_name));
+        let file_name = path.file_name().map_or_else(
+            || source_name.to_owned(),
+            |x| x.to_string_lossy().into_owned(),
+        );
+        let directory = path
+            .parent()
+            .map_or_else(|| String::from("."), |x| x.to_string_lossy().into_owned());
+
+        let (builder, compile_unit) = module.create_debug_info_builder(
+            true,
+            // DWARF has no language code for bf, C is what debuggers handle
+            // best for a function without variables.
+            DWARFSourceLanguage::C,
+            &file_name,
+            &directory,
+            "bf_interpreter",
+            is_optimized,
+            "",
+            0,
+            "",
+            DWARFEmissionKind::Full,
+            0,
+            false,
+            false,
+            "",
+            "",
+        );
+        let file = compile_unit.get_file();
+        let subroutine_type = builder.create_subroutine_type(file, None, &[], DIFlags::PUBLIC);
+        let subprogram = builder.create_function(
+            compile_unit.as_debug_info_scope(),
+            JIT_FUNC_NAME,
+            None,
+            file,
+            first_line as u32,
+            subroutine_type,
+            false,
+            true,
+            first_line as u32,
+            DIFlags::PUBLIC,
+            is_optimized,
+        );
+        function.set_subprogram(subprogram);
+        DebugInfo {
+            builder,
+            subprogram,
+        }
+    }
+
+    /// Attaches `loc` to the instructions `builder` emits from now on.
+    fn set_location(&self, context: &'ctx Context, builder: &Builder<'ctx>, loc: SourceLoc) {
+        let location = self.builder.create_debug_location(
+            context,
+            loc.line as u32,
+            loc.col as u32,
+            self.subprogram.as_debug_info_scope(),
+            None,
+        );
+        builder.set_current_debug_location(location);
+    }
+}
+
+/// A program exported from a shared library as
+/// `int bf_<name>(uint8_t *tape, size_t len, bf_io *io)`.
+pub struct LibraryFunction {
+    pub name: String,
+    /// Name of the `.bf` file in debug info and in the header.
+    pub source_name: String,
+    pub prog: ByteCodeProgram,
+}
+
+impl LibraryFunction {
+    /// Parses and optimizes the program in `src`, read from `path`, and names
+    /// it after the file, with anything that can't be in a C identifier
+    /// replaced by `_`.
+    pub fn parse(path: &str, src: String) -> Self {
+        let stem = Path::new(path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = stem
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let mut prog = Parser::parse_to_bytecode(src);
+        prog.opt_pass_1();
+        prog.opt_pass_2();
+        LibraryFunction {
+            name,
+            source_name: path.to_owned(),
+            prog,
+        }
+    }
+}
+
+/// C declarations for a shared library of `functions`, guarded by
+/// `<NAME>_H` for the file name `name`.
+pub fn c_header(functions: &[LibraryFunction], name: &str, eof_policy: EofPolicy) -> String {
+    let guard: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let eof = match eof_policy {
+        EofPolicy::Zero => "stores 0",
+        EofPolicy::AllOnes => "stores 255",
+        EofPolicy::Unchanged => "leaves the cell unchanged",
+    };
+    let mut header = format!(
+        "/* Generated by bf_interpreter, do not edit. */
+#ifndef {guard}_H
+#define {guard}_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern \"C\" {{
+#endif
+
+#ifndef BF_IO_DEFINED
+#define BF_IO_DEFINED
+/* What `,` and `.` call. `read` returns the next byte of input, or a negative
+   value at the end of input. */
+typedef struct bf_io {{
+    void *ctx;
+    int (*read)(void *ctx);
+    void (*write)(void *ctx, uint8_t c);
+}} bf_io;
+#endif
+
+#define BF_OK {ok}
+/* The program moved the data pointer off the tape. */
+#define BF_OUT_OF_BOUNDS {out_of_bounds}
+
+/* Each program runs on the `len` cells at `tape`, which it expects zeroed,
+   and returns BF_OK or BF_OUT_OF_BOUNDS. At the end of input `,` {eof}. */
+",
+        guard = guard,
+        ok = STATUS_OK,
+        out_of_bounds = STATUS_OUT_OF_BOUNDS,
+        eof = eof,
+    );
+    for function in functions {
+        header += &format!(
+            "\n/* {} */\nint bf_{}(uint8_t *tape, size_t len, bf_io *io);\n",
+            function.source_name, function.name
+        );
+    }
+    header += "\n#ifdef __cplusplus\n}\n#endif\n\n#endif\n";
+    header
+}
+
 pub struct LlvmJit {
     context: inkwell::context::Context,
 }
@@ -53,7 +482,7 @@ impl LlvmJit {
         module: &'a inkwell::module::Module<'b>,
         builder: &'a inkwell::builder::Builder<'b>,
         dataptr_addr: PointerValue,
-        memory: PointerValue,
+        tape: &Tape<'b>,
         matching_blocks: &'a mut Vec<(BasicBlock<'b>, BasicBlock<'b>)>,
     ) {
         let context = &self.context;
@@ -76,20 +505,20 @@ impl LlvmJit {
                 };
 
                 builder.build_store(dataptr_addr, new_dataptr);
+                tape.check_bounds(context, builder, new_dataptr);
             }
             ByteCode::DataIncr(delta) | ByteCode::DataDecr(delta) => {
                 // memory[*dataptr_addr] ( +/- )= delta;
-                if delta > u8::MAX as usize {
-                    panic!("Overflow");
-                }
+                // Cells wrap around, so only delta mod 256 matters.
+                let delta = delta as u8;
                 let dataptr = load!(builder, dataptr_addr, context.i64_type());
 
                 // gep => get element pointer
                 let elem_addr = gep!(
                     builder,
-                    memory,
+                    tape.memory,
                     dataptr.into_int_value(),
-                    context.i64_type()
+                    context.i8_type()
                 );
                 let elem = load!(builder, elem_addr, context.i8_type());
                 let res = match instruction {
@@ -107,54 +536,49 @@ impl LlvmJit {
                 builder.build_store(elem_addr, res);
             }
             ByteCode::Write => {
-                // putchar((i32) memory[*dataptr_addr] )
+                // bf_write(io, memory[*dataptr_addr])
                 let dataptr = load!(builder, dataptr_addr, context.i64_type());
                 let elem_addr = gep!(
                     builder,
-                    memory,
+                    tape.memory,
                     dataptr.into_int_value(),
-                    context.i64_type()
+                    context.i8_type()
                 );
                 let elem = load!(builder, elem_addr, context.i8_type());
-                let elem_as_i32 = builder.build_int_cast(
-                    elem.into_int_value(),
-                    context.i32_type().into(),
-                    "i32 cast",
-                );
                 builder.build_direct_call(
-                    module.get_function(PUTCHAR).unwrap(),
-                    &[elem_as_i32.into()],
-                    "write",
+                    module.get_function(BF_WRITE).unwrap(),
+                    &[tape.io.into(), elem.into()],
+                    "",
                 );
             }
             ByteCode::Read => {
-                // memory[*dataptr_addr]= getchar();
-                let read_result = builder
-                    .build_direct_call(module.get_function(GETCHAR).unwrap(), &[], "read")
-                    .try_as_basic_value()
-                    .left()
-                    .unwrap();
-                let elem = builder.build_int_cast(
-                    read_result.into_int_value(),
-                    context.i8_type().into(),
-                    "i8 cast from i32",
-                );
+                // memory[*dataptr_addr] = bf_read(io, memory[*dataptr_addr]);
                 let dataptr = load!(builder, dataptr_addr, context.i64_type());
                 let elem_addr = gep!(
                     builder,
-                    memory,
+                    tape.memory,
                     dataptr.into_int_value(),
-                    context.i64_type()
+                    context.i8_type()
                 );
+                let current = load!(builder, elem_addr, context.i8_type());
+                let elem = builder
+                    .build_direct_call(
+                        module.get_function(BF_READ).unwrap(),
+                        &[tape.io.into(), current.into()],
+                        "read",
+                    )
+                    .try_as_basic_value()
+                    .left()
+                    .unwrap();
                 builder.build_store(elem_addr, elem);
             }
             ByteCode::JZ => {
                 let dataptr = load!(builder, dataptr_addr, context.i64_type());
                 let offset = gep!(
                     builder,
-                    memory,
+                    tape.memory,
                     dataptr.into_int_value(),
-                    context.i64_type()
+                    context.i8_type()
                 );
                 let val = load!(builder, offset, context.i8_type());
                 let compare = builder.build_int_compare(
@@ -178,9 +602,9 @@ impl LlvmJit {
                 let dataptr = load!(builder, dataptr_addr, context.i64_type());
                 let offset = gep!(
                     builder,
-                    memory,
+                    tape.memory,
                     dataptr.into_int_value(),
-                    context.i64_type()
+                    context.i8_type()
                 );
                 let val = load!(builder, offset, context.i8_type());
                 let compare = builder.build_int_compare(
@@ -197,142 +621,996 @@ impl LlvmJit {
                 let dataptr = load!(builder, dataptr_addr, context.i64_type());
                 let elem_addr = gep!(
                     builder,
-                    memory,
+                    tape.memory,
                     dataptr.into_int_value(),
-                    context.i64_type()
+                    context.i8_type()
                 );
                 builder.build_store(elem_addr, context.i8_type().const_int(0, false));
             }
 
             ByteCode::MoveInStepUntilZero(chng) => {
-                self.jit_instr(
-                    ByteCode::JZ,
-                    module,
-                    builder,
-                    dataptr_addr,
-                    memory,
-                    matching_blocks,
+                // while memory[dataptr] != 0 { dataptr (+/-)= step; }
+                // with dataptr in a register rather than in dataptr_addr
+                let function = tape.out_of_bounds.get_parent().unwrap();
+                let start = load!(builder, dataptr_addr, context.i64_type()).into_int_value();
+                let entry_bb = builder.get_insert_block().unwrap();
+                let scan_bb = context.append_basic_block(function, "scan");
+                let step_bb = context.append_basic_block(function, "scan_step");
+                let end_bb = context.append_basic_block(function, "scan_end");
+                builder.build_unconditional_branch(scan_bb);
+
+                builder.position_at_end(scan_bb);
+                let dataptr = builder.build_phi(context.i64_type(), "scan_dataptr");
+                let dataptr_value = dataptr.as_basic_value().into_int_value();
+                let elem_addr = gep!(builder, tape.memory, dataptr_value, context.i8_type());
+                let elem = load!(builder, elem_addr, context.i8_type());
+                let is_zero = builder.build_int_compare(
+                    inkwell::IntPredicate::EQ,
+                    elem.into_int_value(),
+                    context.i8_type().const_zero(),
+                    "is_zero",
                 );
-                self.jit_instr(
-                    match chng {
-                        Change::Incr(x) => ByteCode::DataPointerIncr(x),
-                        Change::Decr(x) => ByteCode::DataPointerDecr(x),
-                    },
-                    module,
-                    builder,
-                    dataptr_addr,
-                    memory,
-                    matching_blocks,
+                builder.build_conditional_branch(is_zero, end_bb, step_bb);
+
+                builder.position_at_end(step_bb);
+                let next = match chng {
+                    Change::Incr(x) => builder.build_int_add(
+                        dataptr_value,
+                        context.i64_type().const_int(x as u64, false),
+                        "scan_next",
+                    ),
+                    Change::Decr(x) => builder.build_int_sub(
+                        dataptr_value,
+                        context.i64_type().const_int(x as u64, false),
+                        "scan_next",
+                    ),
+                };
+                tape.branch_if_in_bounds(context, builder, dataptr_addr, next, scan_bb);
+                dataptr.add_incoming(&[(&start, entry_bb), (&next, step_bb)]);
+
+                builder.position_at_end(end_bb);
+                builder.build_store(dataptr_addr, dataptr_value);
+            }
+            ByteCode::MultiplyAdd(offset, factor) => {
+                // if memory[dataptr] != 0 {
+                //     memory[dataptr + offset] += memory[dataptr] * factor;
+                // }
+                // Only a loop that runs at all moves the pointer to the target,
+                // so going off the tape is reported at the target.
+                let function = tape.out_of_bounds.get_parent().unwrap();
+                let dataptr = load!(builder, dataptr_addr, context.i64_type()).into_int_value();
+                let elem_addr = gep!(builder, tape.memory, dataptr, context.i8_type());
+                let value = load!(builder, elem_addr, context.i8_type()).into_int_value();
+                let multiply_bb = context.append_basic_block(function, "multiply");
+                let add_bb = context.append_basic_block(function, "multiply_add");
+                let end_bb = context.append_basic_block(function, "multiply_end");
+                let is_zero = builder.build_int_compare(
+                    inkwell::IntPredicate::EQ,
+                    value,
+                    context.i8_type().const_zero(),
+                    "is_zero",
                 );
-                self.jit_instr(
-                    ByteCode::JNZ,
-                    module,
-                    builder,
-                    dataptr_addr,
-                    memory,
-                    matching_blocks,
+                builder.build_conditional_branch(is_zero, end_bb, multiply_bb);
+
+                builder.position_at_end(multiply_bb);
+                let target = builder.build_int_add(
+                    dataptr,
+                    context.i64_type().const_int(offset as u64, true),
+                    "target",
+                );
+                tape.branch_if_in_bounds(context, builder, dataptr_addr, target, add_bb);
+
+                builder.position_at_end(add_bb);
+                let target_addr = gep!(builder, tape.memory, target, context.i8_type());
+                let current = load!(builder, target_addr, context.i8_type()).into_int_value();
+                let product = builder.build_int_mul(
+                    value,
+                    context.i8_type().const_int(factor as u64, false),
+                    "product",
+                );
+                let sum = builder.build_int_add(current, product, "sum");
+                builder.build_store(target_addr, sum);
+                builder.build_unconditional_branch(end_bb);
+
+                builder.position_at_end(end_bb);
+            }
+        }
+    }
+
+    /// Number of cells cleared by a run like `[-]>[-]>[-]` of at least two,
+    /// and the number of instructions it takes.
+    fn cleared_cells(instructions: &[ByteCode]) -> Option<(usize, usize)> {
+        let (mut cells, mut len) = (0, 0);
+        while instructions.get(len) == Some(&ByteCode::SETZERO) {
+            cells += 1;
+            len += 1;
+            if instructions.get(len) != Some(&ByteCode::DataPointerIncr(1))
+                || instructions.get(len + 1) != Some(&ByteCode::SETZERO)
+            {
+                break;
+            }
+            len += 1;
+        }
+        (cells >= 2).then_some((cells, len))
+    }
+
+    /// A run of `cells` clears, as a single `llvm.memset`.
+    fn jit_clear_cells<'b>(
+        &'b self,
+        cells: usize,
+        builder: &Builder<'b>,
+        dataptr_addr: PointerValue<'b>,
+        tape: &Tape<'b>,
+    ) {
+        // count = min(cells, len - dataptr);
+        // memset(&memory[dataptr], 0, count);
+        // *dataptr_addr = min(dataptr + cells - 1, len);
+        // The run stops at the first cell off the tape, having cleared the
+        // ones before it.
+        let context = &self.context;
+        let i64_type = context.i64_type();
+        let dataptr = load!(builder, dataptr_addr, i64_type).into_int_value();
+        let cells_value = i64_type.const_int(cells as u64, false);
+        let remaining = builder.build_int_sub(tape.len, dataptr, "remaining");
+        let fits =
+            builder.build_int_compare(inkwell::IntPredicate::ULE, cells_value, remaining, "fits");
+        let count = builder
+            .build_select(fits, cells_value, remaining, "count")
+            .into_int_value();
+        let elem_addr = gep!(builder, tape.memory, dataptr, context.i8_type());
+        builder
+            .build_memset(elem_addr, 1, context.i8_type().const_zero(), count)
+            .expect("Failed to build memset");
+        let last =
+            builder.build_int_add(dataptr, i64_type.const_int(cells as u64 - 1, false), "last");
+        let last_in_bounds =
+            builder.build_int_compare(inkwell::IntPredicate::ULT, last, tape.len, "in_bounds");
+        let new_dataptr = builder
+            .build_select(last_in_bounds, last, tape.len, "stop")
+            .into_int_value();
+        builder.build_store(dataptr_addr, new_dataptr);
+        tape.check_bounds(context, builder, new_dataptr);
+    }
+
+    /// `object` as an `ObjectFile`, if LLVM can read it.
+    fn parse_object(object: &[u8]) -> Option<ObjectFile> {
+        MemoryBuffer::create_from_memory_range_copy(object, "object")
+            .create_object_file()
+            .ok()
+    }
+
+    /// Size of `JIT_FUNC_NAME` according to the symbol table of `object`.
+    fn object_function_size(object: &[u8]) -> Option<usize> {
+        let object = Self::parse_object(object)?;
+        let size = object
+            .get_symbols()
+            .find(|sym| {
+                sym.get_name()
+                    .is_some_and(|name| name.to_bytes() == JIT_FUNC_NAME.as_bytes())
+            })?
+            .size();
+        Some(size as usize)
+    }
+
+    /// The line table of `object`: where the code for each source location
+    /// starts, as an offset into `JIT_FUNC_NAME`. As the object holds no
+    /// other function, the table is a single sequence, relocated against the
+    /// start of the function.
+    fn line_table(object: &[u8]) -> Option<Vec<(usize, SourceLoc)>> {
+        let object = Self::parse_object(object)?;
+        // A section is only valid as long as the iterator it came from.
+        let mut sections = object.get_sections();
+        let section = sections.find(|section| {
+            section
+                .get_name()
+                .is_some_and(|name| name.to_bytes() == b".debug_line")
+        })?;
+        let debug_line = gimli::DebugLine::new(section.get_contents(), gimli::NativeEndian);
+        let program = debug_line
+            .program(
+                gimli::DebugLineOffset(0),
+                std::mem::size_of::<usize>() as u8,
+                None,
+                None,
+            )
+            .ok()?;
+        let mut rows = program.rows();
+        let mut table = vec![];
+        while let Some((_, row)) = rows.next_row().ok()? {
+            if row.end_sequence() {
+                break;
+            }
+            let col = match row.column() {
+                gimli::ColumnType::LeftEdge => 0,
+                gimli::ColumnType::Column(col) => col.get() as usize,
+            };
+            let loc = SourceLoc {
+                line: row.line().map_or(0, |line| line.get() as usize),
+                col,
+            };
+            table.push((row.address() as usize, loc));
+        }
+        Some(table)
+    }
+
+    /// Perf map regions for the `size` bytes of code `object` has for
+    /// `prog`, each named after the innermost loop of the source locations
+    /// the line table gives it, as the other JITs name theirs. Code LLVM
+    /// made up, at line 0, goes with the code before it. Without a line
+    /// table, the whole function is a single region.
+    fn perf_map_regions(object: &[u8], prog: &ByteCodeProgram, size: usize) -> Vec<CodeRegion> {
+        let whole_function = || {
+            vec![CodeRegion {
+                name: JIT_FUNC_NAME.to_owned(),
+                start: 0,
+                end: size,
+            }]
+        };
+        let Some(table) = Self::line_table(object) else {
+            return whole_function();
+        };
+
+        // (source location of '[', source location of ']')
+        let mut loops = vec![];
+        let mut stack = vec![];
+        // the innermost loop around every instruction
+        let mut innermost = BTreeMap::new();
+        for (instruction, span) in prog.instructions.iter().zip(&prog.spans) {
+            if *instruction == ByteCode::JZ {
+                loops.push((span.start, span.start));
+                stack.push(loops.len() - 1);
+            }
+            innermost.insert(span.start, stack.last().copied());
+            if *instruction == ByteCode::JNZ {
+                let id = stack.pop().expect("Invalid program");
+                loops[id].1 = span.start;
+            }
+        }
+
+        let mut regions: Vec<CodeRegion> = vec![];
+        let mut name = String::from(PROGRAM_REGION);
+        for (i, &(start, loc)) in table.iter().enumerate() {
+            let end = table.get(i + 1).map_or(size, |&(next, _)| next).min(size);
+            if loc.line != 0 {
+                name = match innermost.get(&loc) {
+                    Some(Some(id)) => loop_region_name(loops[*id].0, loops[*id].1),
+                    _ => String::from(PROGRAM_REGION),
+                };
+            }
+            if start >= end {
+                continue;
+            }
+            match regions.last_mut() {
+                Some(last) if last.name == name && last.end == start => last.end = end,
+                _ => regions.push(CodeRegion {
+                    name: name.clone(),
+                    start,
+                    end,
+                }),
+            }
+        }
+        if regions.is_empty() {
+            return whole_function();
+        }
+        regions
+    }
+
+    /// Target machine for the host triple with a generic CPU, so that the
+    /// code it emits runs on any machine of the same architecture.
+    fn host_target_machine(
+        opt_level: OptimizationLevel,
+        reloc_mode: RelocMode,
+        code_model: CodeModel,
+    ) -> Option<TargetMachine> {
+        let triple = TargetMachine::get_default_triple();
+        let target = Target::from_triple(&triple).ok()?;
+        target.create_target_machine(&triple, "", "", opt_level, reloc_mode, code_model)
+    }
+
+    /// ```c
+    /// int main() {
+    ///     return __llvm_jit(calloc(MEMORY_SIZE, 1), MEMORY_SIZE, NULL).status;
+    /// }
+    /// ```
+    fn add_main<'a>(&'a self, module: &Module<'a>) {
+        let context = &self.context;
+        let i64_type = context.i64_type();
+        let i8_ptr_type = context.i8_type().ptr_type(AddressSpace::default());
+        let calloc = module.add_function(
+            "calloc",
+            i8_ptr_type.fn_type(&[i64_type.into(), i64_type.into()], false),
+            Some(Linkage::External),
+        );
+        let main = module.add_function(
+            "main",
+            context.i32_type().fn_type(&[], false),
+            Some(Linkage::External),
+        );
+        let builder = context.create_builder();
+        builder.position_at_end(context.append_basic_block(main, "entry"));
+        let tape_len = i64_type.const_int(MEMORY_SIZE as u64, false);
+        let tape = builder
+            .build_direct_call(
+                calloc,
+                &[tape_len.into(), i64_type.const_int(1, false).into()],
+                "tape",
+            )
+            .try_as_basic_value()
+            .left()
+            .unwrap();
+        let result = builder
+            .build_direct_call(
+                module.get_function(JIT_FUNC_NAME).unwrap(),
+                &[
+                    tape.into(),
+                    tape_len.into(),
+                    i8_ptr_type.const_null().into(),
+                ],
+                "result",
+            )
+            .try_as_basic_value()
+            .left()
+            .unwrap();
+        let status = builder
+            .build_extract_value(result.into_struct_value(), 1, "status")
+            .unwrap();
+        builder.build_return(Some(&status));
+    }
+
+    /// Defines `BF_WRITE`/`BF_READ` with libc's `putchar`/`getchar`, for
+    /// executables that don't have the Rust runtime to call into.
+    fn add_libc_runtime<'a>(&'a self, module: &Module<'a>, eof_policy: EofPolicy) {
+        let context = &self.context;
+        let i32_type = context.i32_type();
+        let i8_type = context.i8_type();
+        let putchar = module.add_function(
+            "putchar",
+            i32_type.fn_type(&[i32_type.into()], false),
+            Some(Linkage::External),
+        );
+        let getchar = module.add_function(
+            "getchar",
+            i32_type.fn_type(&[], false),
+            Some(Linkage::External),
+        );
+        let builder = context.create_builder();
+
+        let bf_write = module.get_function(BF_WRITE).unwrap();
+        bf_write.set_linkage(Linkage::Private);
+        builder.position_at_end(context.append_basic_block(bf_write, "entry"));
+        let c = bf_write.get_nth_param(1).unwrap().into_int_value();
+        let c = builder.build_int_z_extend(c, i32_type, "c");
+        builder.build_direct_call(putchar, &[c.into()], "");
+        builder.build_return(None);
+
+        let bf_read = module.get_function(BF_READ).unwrap();
+        bf_read.set_linkage(Linkage::Private);
+        builder.position_at_end(context.append_basic_block(bf_read, "entry"));
+        let c = builder
+            .build_direct_call(getchar, &[], "c")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value();
+        // EOF is the only negative value getchar returns
+        let is_eof =
+            builder.build_int_compare(inkwell::IntPredicate::SLT, c, i32_type.const_zero(), "eof");
+        let on_eof = match eof_policy {
+            EofPolicy::Unchanged => bf_read.get_nth_param(1).unwrap().into_int_value(),
+            policy => i8_type.const_int(policy.on_eof(0) as u64, false),
+        };
+        let c = builder.build_int_truncate(c, i8_type, "byte");
+        let result = builder.build_select(is_eof, on_eof, c, "result");
+        builder.build_return(Some(&result));
+    }
+
+    /// `bf_write` and `bf_read` for shared libraries, calling back into the
+    /// `bf_io` the caller passes in:
+    ///
+    /// ```c
+    /// void bf_write(bf_io *io, uint8_t c) { io->write(io->ctx, c); }
+    /// uint8_t bf_read(bf_io *io, uint8_t current) {
+    ///     int c = io->read(io->ctx);
+    ///     return c < 0 ? <eof_policy>(current) : c;
+    /// }
+    /// ```
+    fn add_callback_runtime<'a>(&'a self, module: &Module<'a>, eof_policy: EofPolicy) {
+        let context = &self.context;
+        let i32_type = context.i32_type();
+        let i8_type = context.i8_type();
+        let ptr_type = i8_type.ptr_type(AddressSpace::default());
+        let io_type = context.struct_type(&[ptr_type.into(); 3], false);
+        let read_type = i32_type.fn_type(&[ptr_type.into()], false);
+        let write_type = context
+            .void_type()
+            .fn_type(&[ptr_type.into(), i8_type.into()], false);
+        let builder = context.create_builder();
+        // Loads field `index` of the bf_io at `io`.
+        let field = |io, index, name| {
+            let addr = builder.build_struct_gep(io_type, io, index, name).unwrap();
+            load!(builder, addr, ptr_type).into_pointer_value()
+        };
+
+        let bf_write = module.get_function(BF_WRITE).unwrap();
+        bf_write.set_linkage(Linkage::Private);
+        builder.position_at_end(context.append_basic_block(bf_write, "entry"));
+        let io = bf_write.get_nth_param(0).unwrap().into_pointer_value();
+        let (ctx, write) = (field(io, 0, "ctx"), field(io, 2, "write"));
+        let c = bf_write.get_nth_param(1).unwrap();
+        builder.build_indirect_call(write_type, write, &[ctx.into(), c.into()], "");
+        builder.build_return(None);
+
+        let bf_read = module.get_function(BF_READ).unwrap();
+        bf_read.set_linkage(Linkage::Private);
+        builder.position_at_end(context.append_basic_block(bf_read, "entry"));
+        let io = bf_read.get_nth_param(0).unwrap().into_pointer_value();
+        let (ctx, read) = (field(io, 0, "ctx"), field(io, 1, "read"));
+        let c = builder
+            .build_indirect_call(read_type, read, &[ctx.into()], "c")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value();
+        let is_eof =
+            builder.build_int_compare(inkwell::IntPredicate::SLT, c, i32_type.const_zero(), "eof");
+        let on_eof = match eof_policy {
+            EofPolicy::Unchanged => bf_read.get_nth_param(1).unwrap().into_int_value(),
+            policy => i8_type.const_int(policy.on_eof(0) as u64, false),
+        };
+        let c = builder.build_int_truncate(c, i8_type, "byte");
+        let result = builder.build_select(is_eof, on_eof, c, "result");
+        builder.build_return(Some(&result));
+    }
+
+    /// Renames the `JIT_FUNC_NAME` of `module` to a private `bf_<name>_impl`,
+    /// and exports it as
+    ///
+    /// ```c
+    /// int bf_<name>(uint8_t *tape, size_t len, bf_io *io) {
+    ///     return bf_<name>_impl(tape, len, io).status;
+    /// }
+    /// ```
+    fn export_library_function<'a>(&'a self, module: &Module<'a>, name: &str) {
+        let context = &self.context;
+        let implementation = module.get_function(JIT_FUNC_NAME).unwrap();
+        implementation
+            .as_global_value()
+            .set_name(&format!("bf_{}_impl", name));
+        implementation.set_linkage(Linkage::Private);
+
+        let ptr_type = context.i8_type().ptr_type(AddressSpace::default());
+        let i64_type = context.i64_type();
+        let function = module.add_function(
+            &format!("bf_{}", name),
+            context
+                .i32_type()
+                .fn_type(&[ptr_type.into(), i64_type.into(), ptr_type.into()], false),
+            Some(Linkage::External),
+        );
+        let builder = context.create_builder();
+        builder.position_at_end(context.append_basic_block(function, "entry"));
+        let args = function
+            .get_param_iter()
+            .map(|param| param.into())
+            .collect::<Vec<_>>();
+        let result = builder
+            .build_direct_call(implementation, &args, "result")
+            .try_as_basic_value()
+            .left()
+            .unwrap();
+        let status = builder
+            .build_extract_value(result.into_struct_value(), 1, "status")
+            .unwrap();
+        builder.build_return(Some(&status));
+    }
+
+    /// A module exporting each of `functions`, with the `bf_io` runtime.
+    fn library_module(
+        &self,
+        functions: &[LibraryFunction],
+        options: &JitOptions,
+        compilation: &mut Compilation,
+    ) -> Module<'_> {
+        for (i, function) in functions.iter().enumerate() {
+            if functions[..i].iter().any(|f| f.name == function.name) {
+                panic!(
+                    "Two programs would both be exported as bf_{}",
+                    function.name
                 );
             }
         }
+        let module = self.context.create_module("bf_library");
+        for function in functions {
+            let options = JitOptions {
+                source_name: Some(function.source_name.clone()),
+                ..options.clone()
+            };
+            let program = self.build_module(&function.prog, &options, compilation);
+            self.export_library_function(&program, &function.name);
+            module
+                .link_in_module(program)
+                .unwrap_or_else(|e| panic!("Failed to link bf_{}: {}", function.name, e));
+        }
+        if module.get_function(BF_WRITE).is_some() {
+            self.add_callback_runtime(&module, options.eof_policy);
+        }
+        module
+    }
+
+    /// Compiles `functions` into the static library `output`, an archive of
+    /// a single object file made with `$AR`, or `ar`.
+    pub fn build_static_library(
+        &self,
+        functions: &[LibraryFunction],
+        output: &Path,
+        options: &JitOptions,
+    ) {
+        let mut compilation = Compilation::new(options);
+        let module = self.library_module(functions, options, &mut compilation);
+        let object = output.with_extension("o");
+        compilation.emit_file(&module, FileType::Object, &object);
+        compilation.timings.time("archive", || {
+            let archiver = std::env::var("AR").unwrap_or_else(|_| String::from("ar"));
+            // Replacing members would keep objects from earlier builds around.
+            let _ = std::fs::remove_file(output);
+            let status = Command::new(&archiver)
+                .arg("crs")
+                .arg(output)
+                .arg(&object)
+                .status()
+                .unwrap_or_else(|e| panic!("Failed to run {}: {}", archiver, e));
+            if !status.success() {
+                panic!("{} failed to create {}", archiver, output.display());
+            }
+        });
+        let _ = std::fs::remove_file(&object);
+        compilation.timings.report();
+    }
+
+    /// Compiles `functions` into the shared library `output`, with their
+    /// declarations written to the C header `header`.
+    pub fn build_shared_library(
+        &self,
+        functions: &[LibraryFunction],
+        output: &Path,
+        header: &Path,
+        options: &JitOptions,
+    ) {
+        let mut compilation = Compilation::new(options);
+        let module = self.library_module(functions, options, &mut compilation);
+        let object = output.with_extension("o");
+        compilation.emit_file(&module, FileType::Object, &object);
+        compilation
+            .timings
+            .time("link", || Self::link(&object, output, &["-shared"]));
+        let _ = std::fs::remove_file(&object);
+
+        let guard = header
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        std::fs::write(header, c_header(functions, &guard, options.eof_policy))
+            .unwrap_or_else(|e| panic!("Failed to write {}: {}", header.display(), e));
+        compilation.timings.report();
+    }
+
+    /// Links an object file with the system C compiler (`$CC`, or `cc`),
+    /// which provides the C runtime and libc.
+    fn link(object: &Path, output: &Path, args: &[&str]) {
+        let linker = std::env::var("CC").unwrap_or_else(|_| String::from("cc"));
+        let status = Command::new(&linker)
+            .args(args)
+            .arg(object)
+            .arg("-o")
+            .arg(output)
+            .status()
+            .unwrap_or_else(|e| panic!("Failed to run {}: {}", linker, e));
+        if !status.success() {
+            panic!("{} failed to link {}", linker, output.display());
+        }
     }
+
+    fn build_executable<'a>(
+        &'a self,
+        module: &Module<'a>,
+        output: &Path,
+        eof_policy: EofPolicy,
+        compilation: &mut Compilation,
+    ) {
+        self.add_libc_runtime(module, eof_policy);
+        self.add_main(module);
+        let object = output.with_extension("o");
+        compilation.emit_file(module, FileType::Object, &object);
+        compilation
+            .timings
+            .time("link", || Self::link(&object, output, &[]));
+        let _ = std::fs::remove_file(&object);
+    }
+
     pub fn jit(&self, instructions: Vec<ByteCode>, action: Action) {
-        // - Setup context
-        // - Setup module
-        // - Setup builder
-        // - Setup execution engine
-        // let context = Context::create();
-        // let module = context.create_module("bf_module");
-        // let builder = context.create_builder();
+        let prog = ByteCodeProgram {
+            instructions,
+            spans: vec![],
+        };
+        self.jit_with_options(&prog, action, &JitOptions::default());
+    }
 
-        // let execution_engine = module
-        //     .create_jit_execution_engine(OptimizationLevel::None)
-        //     .expect("Failed to create execution_engine");
+    /// Debug info needs the source spans of `prog`, so `JitOptions::debug_info`
+    /// has no effect on programs without them.
+    pub fn jit_with_options(&self, prog: &ByteCodeProgram, action: Action, options: &JitOptions) {
+        let mut compilation = Compilation::new(options);
+        if let Action::Execute = action {
+            let mut tape = vec![0; MEMORY_SIZE];
+            let (stdin, stdout) = (std::io::stdin(), std::io::stdout());
+            let mut io = Io::new(stdin.lock(), stdout.lock(), options.eof_policy);
+            let result = self.run(prog, &mut tape, &mut io, options, &mut compilation);
+            io.finish().expect("Failed to write output");
+            if result.status == STATUS_OUT_OF_BOUNDS {
+                panic!("Data pointer out of bounds: {}", result.dataptr);
+            }
+            compilation.timings.report();
+            return;
+        }
 
-        // - Create function for the bf program
-        // let context = context::create();
+        let module = self.build_module(prog, options, &mut compilation);
+        match action {
+            Action::Execute => unreachable!(),
+            Action::Print => println!("{}", compilation.optimized_ir(&module)),
+            Action::BuildExecutable(output) => {
+                self.build_executable(&module, &output, options.eof_policy, &mut compilation)
+            }
+            Action::EmitBitcode(output) => {
+                let machine = compilation.aot_target_machine(&module);
+                compilation.optimize(&module, &machine);
+                if !module.write_bitcode_to_path(&output) {
+                    panic!("Failed to write {}", output.display());
+                }
+            }
+            Action::EmitAssembly(output) => {
+                compilation.emit_file(&module, FileType::Assembly, &output)
+            }
+            Action::EmitObject(output) => compilation.emit_file(&module, FileType::Object, &output),
+        }
+        compilation.timings.report();
+    }
+
+    /// Compiles and runs `prog` on `tape`, which the program sees as zeroed
+    /// memory it can't move past, and which keeps its contents afterwards.
+    /// `options.eof_policy` is ignored, `io` has its own.
+    pub fn execute<R: Read, W: Write>(
+        &self,
+        prog: &ByteCodeProgram,
+        tape: &mut [u8],
+        io: &mut Io<R, W>,
+        options: &JitOptions,
+    ) -> JitResult {
+        let (result, timings) = self.execute_timed(prog, tape, io, options);
+        timings.report();
+        result
+    }
+
+    /// `execute`, returning the timings instead of reporting them. With
+    /// `options.time_stages` they include the code size.
+    pub fn execute_timed<R: Read, W: Write>(
+        &self,
+        prog: &ByteCodeProgram,
+        tape: &mut [u8],
+        io: &mut Io<R, W>,
+        options: &JitOptions,
+    ) -> (JitResult, StageTimings) {
+        let mut compilation = Compilation::new(options);
+        let result = self.run(prog, tape, io, options, &mut compilation);
+        (result, compilation.timings)
+    }
+
+    /// Compiles `prog` to object code, or takes it from `options.code_cache`
+    /// if there is one, and runs it.
+    fn run<R: Read, W: Write>(
+        &self,
+        prog: &ByteCodeProgram,
+        tape: &mut [u8],
+        io: &mut Io<R, W>,
+        options: &JitOptions,
+        compilation: &mut Compilation,
+    ) -> JitResult {
+        // The perf map splits the code into loops along the line table.
+        let with_line_table;
+        let options = if options.perf_map && !options.debug_info {
+            with_line_table = JitOptions {
+                debug_info: true,
+                ..options.clone()
+            };
+            &with_line_table
+        } else {
+            options
+        };
+        let (object, cached) = match &options.code_cache {
+            Some(cache) => self.cached_object(prog, cache, options, compilation),
+            None => (self.compile_object(prog, options, compilation), false),
+        };
+        let name = if cached {
+            format!("cached object for {}", options.source_name())
+        } else {
+            format!("object for {}", options.source_name())
+        };
+        Self::run_object(&object, &name, prog, tape, io, options, compilation)
+    }
 
+    /// Object code for `prog`, for `ObjectJit` to load.
+    fn compile_object(
+        &self,
+        prog: &ByteCodeProgram,
+        options: &JitOptions,
+        compilation: &mut Compilation,
+    ) -> Vec<u8> {
+        let module = self.build_module(prog, options, compilation);
+        let machine = compilation.aot_target_machine(&module);
+        compilation.optimize(&module, &machine);
+        compilation
+            .timings
+            .time("codegen", || {
+                machine.write_to_memory_buffer(&module, FileType::Object)
+            })
+            .expect("Failed to compile module")
+            .as_slice()
+            .to_vec()
+    }
+
+    /// Everything the object code for `prog` depends on.
+    fn cache_key(prog: &ByteCodeProgram, options: &JitOptions) -> Vec<u8> {
+        let (major, minor, patch) = inkwell::support::get_llvm_version();
+        let mut key = format!(
+            "{} {}\nLLVM {}.{}.{}\n{}\n-O{} {}\n",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            major,
+            minor,
+            patch,
+            TargetMachine::get_default_triple()
+                .as_str()
+                .to_string_lossy(),
+            options.opt_level(),
+            options.pass_pipeline(),
+        );
+        if options.debug_info && !prog.spans.is_empty() {
+            key += &format!("{} {:?}\n", options.source_name(), prog.spans);
+        }
+        key += &format!("{:?}", prog.instructions);
+        key.into_bytes()
+    }
+
+    /// The object code for `prog` from `cache`, compiled and added on a
+    /// miss, and whether it was in the cache.
+    fn cached_object(
+        &self,
+        prog: &ByteCodeProgram,
+        cache: &CodeCache,
+        options: &JitOptions,
+        compilation: &mut Compilation,
+    ) -> (Vec<u8>, bool) {
+        let key = Self::cache_key(prog, options);
+        match compilation.timings.time("cache", || cache.get(&key)) {
+            Some(object) => (object, true),
+            None => {
+                let object = self.compile_object(prog, options, compilation);
+                // The program still runs, just gets compiled again next time.
+                if let Err(e) = cache.insert(&key, &object) {
+                    eprintln!(
+                        "Failed to add to the code cache in {}: {}",
+                        cache.dir().display(),
+                        e
+                    );
+                }
+                (object, false)
+            }
+        }
+    }
+
+    /// Loads `object`, the code for `prog` called `name` in LLVM's errors,
+    /// and runs its `JIT_FUNC_NAME` on `tape`.
+    fn run_object<R: Read, W: Write>(
+        object: &[u8],
+        name: &str,
+        prog: &ByteCodeProgram,
+        tape: &mut [u8],
+        io: &mut Io<R, W>,
+        options: &JitOptions,
+        compilation: &mut Compilation,
+    ) -> JitResult {
+        let runtime = [
+            (BF_WRITE, io::bf_write::<R, W> as *const () as usize),
+            (BF_READ, io::bf_read::<R, W> as *const () as usize),
+        ];
+        let (jit, address) = compilation
+            .timings
+            .time("load", || {
+                let jit = ObjectJit::load(object, name, &runtime)?;
+                let address = jit.lookup(JIT_FUNC_NAME)?;
+                Ok::<_, String>((jit, address))
+            })
+            .unwrap_or_else(|e| panic!("Failed to load object code: {}", e));
+
+        let size = if options.perf_map || compilation.timings.enabled() {
+            Self::object_function_size(object)
+        } else {
+            None
+        };
+        if let Some(size) = size {
+            compilation.timings.set_code_size(size);
+        }
+        if options.perf_map {
+            if let Some(size) = size {
+                let regions = Self::perf_map_regions(object, prog, size);
+                perf_map::append_to_perf_map(address, &regions).expect("Failed to write perf map");
+            }
+        }
+
+        let result = unsafe {
+            let bf_fn: unsafe extern "C" fn(*mut u8, i64, *mut u8) -> JitResult =
+                std::mem::transmute(address);
+            let (memory, len) = (tape.as_mut_ptr(), tape.len() as i64);
+            let io = io as *mut Io<R, W> as *mut u8;
+            compilation
+                .timings
+                .time("execute", || bf_fn(memory, len, io))
+        };
+        drop(jit);
+        result
+    }
+
+    fn build_module(
+        &self,
+        prog: &ByteCodeProgram,
+        options: &JitOptions,
+        compilation: &mut Compilation,
+    ) -> Module<'_> {
+        let irgen_start = Instant::now();
         let context = &self.context;
-        let void_type = context.void_type();
         let module = context.create_module("bf_module");
         let builder = context.create_builder();
 
-        let fn_type = void_type.fn_type(&[], false);
+        let i64_type = context.i64_type();
+        let i8_ptr_type = context.i8_type().ptr_type(AddressSpace::default());
+        let result_type = context.struct_type(&[i64_type.into(), context.i32_type().into()], false);
+        let fn_type = result_type.fn_type(
+            &[i8_ptr_type.into(), i64_type.into(), i8_ptr_type.into()],
+            false,
+        );
         let function = module.add_function(JIT_FUNC_NAME, fn_type, Some(Linkage::External));
+        // Nothing else, bf_write and bf_read included, touches the tape while
+        // the program runs, which lets LLVM keep cells in registers and
+        // vectorize.
+        let noalias =
+            context.create_enum_attribute(Attribute::get_named_enum_kind_id("noalias"), 0);
+        function.add_attribute(AttributeLoc::Param(0), noalias);
+        let i8_type = context.i8_type();
         module.add_function(
-            PUTCHAR,
+            BF_WRITE,
             context
-                .i32_type()
-                .fn_type(&[BasicMetadataTypeEnum::IntType(context.i32_type())], false),
+                .void_type()
+                .fn_type(&[i8_ptr_type.into(), i8_type.into()], false),
             Some(Linkage::External),
         );
         module.add_function(
-            GETCHAR,
-            // void_type.fn_type(&[BasicMetadataTypeEnum::IntType(context.i32_type())], false),
-            context.i32_type().fn_type(&[], false),
+            BF_READ,
+            i8_type.fn_type(&[i8_ptr_type.into(), i8_type.into()], false),
             Some(Linkage::External),
         );
         let entry = context.append_basic_block(function, "entry");
+        let tape = Tape {
+            memory: function.get_nth_param(0).unwrap().into_pointer_value(),
+            len: function.get_nth_param(1).unwrap().into_int_value(),
+            io: function.get_nth_param(2).unwrap().into_pointer_value(),
+            out_of_bounds: context.append_basic_block(function, "out_of_bounds"),
+        };
 
         builder.position_at_end(entry);
 
-        let memory = builder.build_array_alloca(
-            context.i8_type(),
-            context.i64_type().const_int(MEMORY_SIZE as u64, false),
-            "memory",
-        );
-        builder
-            .build_memset(
-                memory,
-                Layout::new::<i8>().align() as u32,
-                context.i8_type().const_int(0, false),
-                context.i64_type().const_int(MEMORY_SIZE as u64, false),
-            )
-            .unwrap();
+        let debug_info = match (prog.spans.first(), prog.spans.last()) {
+            (Some(first), Some(last)) if options.debug_info => {
+                let debug_info = DebugInfo::new(
+                    context,
+                    &module,
+                    function,
+                    options.source_name(),
+                    first.start.line,
+                    compilation.opt_level != OptimizationLevel::None,
+                );
+                // the entry code belongs to the first instruction
+                debug_info.set_location(context, &builder, first.start);
+                Some((debug_info, last.end))
+            }
+            _ => None,
+        };
 
         // stores the current index
-        let dataptr_addr = builder.build_alloca(context.i64_type(), "dataptr_addr");
-        builder.build_store(dataptr_addr, context.i64_type().const_int(0, false));
+        let dataptr_addr = builder.build_alloca(i64_type, "dataptr_addr");
+        let zero = i64_type.const_int(0, false);
+        builder.build_store(dataptr_addr, zero);
+        // an empty tape doesn't even have a current cell
+        tape.check_bounds(context, &builder, zero);
 
         let mut matching_blocks = vec![];
-        for instr in instructions {
+        let mut index = 0;
+        while index < prog.instructions.len() {
+            if let Some((debug_info, _)) = &debug_info {
+                debug_info.set_location(context, &builder, prog.spans[index].start);
+            }
+            if let Some((cells, len)) = Self::cleared_cells(&prog.instructions[index..]) {
+                self.jit_clear_cells(cells, &builder, dataptr_addr, &tape);
+                index += len;
+                continue;
+            }
             self.jit_instr(
-                instr,
+                prog.instructions[index],
                 &module,
                 &builder,
                 dataptr_addr,
-                memory,
+                &tape,
                 &mut matching_blocks,
             );
+            index += 1;
         }
-        builder.build_return(None);
+        if let Some((debug_info, end)) = &debug_info {
+            debug_info.set_location(context, &builder, *end);
+        }
+        let status_type = context.i32_type();
+        let dataptr = load!(builder, dataptr_addr, i64_type);
+        builder.build_aggregate_return(&[
+            dataptr,
+            status_type.const_int(STATUS_OK as u64, false).into(),
+        ]);
 
-        match action {
-            Action::Print => {
-                println!("{}", module.to_string());
-            }
-            Action::Execute => {
-                let execution_engine = module
-                    .create_jit_execution_engine(inkwell::OptimizationLevel::Aggressive)
-                    .expect("Failed to create execution engine");
-
-                unsafe {
-                    let bf_fn = execution_engine
-                        .get_function::<unsafe extern "C" fn() -> ()>(JIT_FUNC_NAME)
-                        .unwrap();
-                    bf_fn.call();
-                }
-            }
+        builder.position_at_end(tape.out_of_bounds);
+        let dataptr = load!(builder, dataptr_addr, i64_type);
+        builder.build_aggregate_return(&[
+            dataptr,
+            status_type
+                .const_int(STATUS_OUT_OF_BOUNDS as u64, false)
+                .into(),
+        ]);
+
+        if let Some((debug_info, _)) = &debug_info {
+            debug_info.builder.finalize();
         }
+        compilation.timings.record("irgen", irgen_start.elapsed());
+        module
     }
-    pub fn parse_and_act(src_code: String, action: Action) {
-        // Get the program parsed to bytecode
-        let prog = Parser::parse_to_bytecode(src_code);
+
+    /// A compiler for the machine it runs on.
+    pub fn for_host() -> Self {
         inkwell::targets::Target::initialize_native(&InitializationConfig::default())
             .expect("Failed to initialize native target");
-        let context = Context::create();
-        let compiler = Self { context };
+        Self {
+            context: Context::create(),
+        }
+    }
 
-        compiler.jit(prog.instructions, action);
+    /// Parses and compiles the `(path, source)` pairs in `sources` into the
+    /// shared library `output`, see `build_shared_library`.
+    pub fn parse_and_build_shared_library(
+        sources: Vec<(String, String)>,
+        output: &Path,
+        header: &Path,
+        options: &JitOptions,
+    ) {
+        let functions = sources
+            .into_iter()
+            .map(|(path, src)| LibraryFunction::parse(&path, src))
+            .collect::<Vec<_>>();
+        Self::for_host().build_shared_library(&functions, output, header, options);
+    }
+
+    pub fn parse_and_act(src_code: String, action: Action) {
+        Self::parse_and_act_with_options(src_code, action, &JitOptions::default());
+    }
+
+    pub fn parse_and_act_with_options(src_code: String, action: Action, options: &JitOptions) {
+        // Get the program parsed to bytecode
+        let mut prog = Parser::parse_to_bytecode(src_code);
+        prog.opt_pass_1();
+        prog.opt_pass_2();
+        Self::for_host().jit_with_options(&prog, action, options);
     }
 }
 
@@ -341,8 +1619,13 @@ mod tests {
 
     use inkwell::context::Context;
 
-    use super::ByteCode;
-    use super::LlvmJit;
+    use std::process::Command;
+
+    use super::{ByteCode, JitResult, LlvmJit, STATUS_OK, STATUS_OUT_OF_BOUNDS};
+    use crate::code_cache::CodeCache;
+    use crate::io::{EofPolicy, Io};
+    use crate::jit_utils::JitOptions;
+    use crate::parser::Parser;
 
     #[test]
     fn test_emitting() {
@@ -367,6 +1650,324 @@ mod tests {
         // compiler.jit(vec![ByteCode::Read, ByteCode::Write]); // Works
     }
 
+    #[test]
+    fn build_executable() {
+        let code = include_str!("../programs/hello_world.bf");
+        let exe = std::env::temp_dir().join(format!("bf-hello-{}", std::process::id()));
+        LlvmJit::parse_and_act(code.to_owned(), super::Action::BuildExecutable(exe.clone()));
+
+        let output = Command::new(&exe).output().unwrap();
+        std::fs::remove_file(&exe).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"Hello World!\n");
+    }
+
+    #[test]
+    fn shared_library() {
+        let dir = std::env::temp_dir().join(format!("bf-library-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (library, header) = (dir.join("libbf.so"), dir.join("bf.h"));
+        let sources = vec![
+            (
+                String::from("programs/hello_world.bf"),
+                include_str!("../programs/hello_world.bf").to_owned(),
+            ),
+            (String::from("cat-1.bf"), String::from(",[.,]")),
+        ];
+        let options = JitOptions {
+            eof_policy: EofPolicy::Zero,
+            ..Default::default()
+        };
+        LlvmJit::parse_and_build_shared_library(sources, &library, &header, &options);
+        let header_text = std::fs::read_to_string(&header).unwrap();
+        assert!(header_text.starts_with("/* Generated by bf_interpreter"));
+        assert!(header_text.contains("#ifndef BF_H"));
+        assert!(header_text.contains("int bf_cat_1(uint8_t *tape, size_t len, bf_io *io);"));
+
+        let caller = dir.join("caller.c");
+        std::fs::write(
+            &caller,
+            r#"#include <stdio.h>
+#include "bf.h"
+
+static int read_stdin(void *ctx) { return getchar(); }
+static void write_stdout(void *ctx, uint8_t c) { putchar(c); }
+
+int main(void) {
+    bf_io io = { NULL, read_stdin, write_stdout };
+    uint8_t tape[100] = { 0 }, cell = 0;
+    if (bf_hello_world(tape, sizeof tape, &io) != BF_OK)
+        return 1;
+    if (bf_cat_1(&cell, 1, &io) != BF_OK)
+        return 2;
+    return 0;
+}
+"#,
+        )
+        .unwrap();
+        let exe = dir.join("caller");
+        let status = Command::new("cc")
+            .arg(&caller)
+            .arg(&library)
+            .arg(format!("-Wl,-rpath,{}", dir.display()))
+            .arg("-o")
+            .arg(&exe)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let mut child = Command::new(&exe)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        std::io::Write::write_all(&mut child.stdin.take().unwrap(), b"abc").unwrap();
+        let output = child.wait_with_output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"Hello World!\nabc");
+    }
+
+    #[test]
+    fn emit_files() {
+        let code = include_str!("../programs/hello_world.bf");
+        let dir = std::env::temp_dir();
+        let bitcode = dir.join(format!("bf-hello-{}.bc", std::process::id()));
+        let assembly = dir.join(format!("bf-hello-{}.s", std::process::id()));
+        let object = dir.join(format!("bf-hello-{}.o", std::process::id()));
+        LlvmJit::parse_and_act(code.to_owned(), super::Action::EmitBitcode(bitcode.clone()));
+        LlvmJit::parse_and_act(
+            code.to_owned(),
+            super::Action::EmitAssembly(assembly.clone()),
+        );
+        LlvmJit::parse_and_act(code.to_owned(), super::Action::EmitObject(object.clone()));
+
+        let bitcode_bytes = std::fs::read(&bitcode).unwrap();
+        let assembly_text = std::fs::read_to_string(&assembly).unwrap();
+        let object_bytes = std::fs::read(&object).unwrap();
+        for path in [&bitcode, &assembly, &object] {
+            std::fs::remove_file(path).unwrap();
+        }
+        assert_eq!(&bitcode_bytes[..4], b"BC\xc0\xde");
+        assert!(assembly_text.contains("__llvm_jit:"));
+        assert_eq!(&object_bytes[..4], b"\x7fELF");
+    }
+
+    #[test]
+    fn custom_pipeline() {
+        inkwell::targets::Target::initialize_native(&Default::default()).unwrap();
+        let compiler = LlvmJit {
+            context: Context::create(),
+        };
+        let prog =
+            Parser::parse_to_bytecode(String::from(include_str!("../programs/hello_world.bf")));
+        let ir = |passes: &str| {
+            let options = JitOptions {
+                opt_level: Some(0),
+                passes: Some(String::from(passes)),
+                ..JitOptions::default()
+            };
+            let mut compilation = super::Compilation::new(&options);
+            let module = compiler.build_module(&prog, &options, &mut compilation);
+            compilation.optimized_ir(&module)
+        };
+
+        assert!(!ir("mem2reg,instcombine,simplifycfg").contains("alloca"));
+        assert!(ir("").contains("alloca"));
+    }
+
+    #[test]
+    fn debug_info() {
+        let code = include_str!("../programs/hello_world.bf");
+        let object = std::env::temp_dir().join(format!("bf-debug-{}.o", std::process::id()));
+        let options = JitOptions {
+            debug_info: true,
+            source_name: Some(String::from("programs/hello_world.bf")),
+            opt_level: Some(0),
+            ..JitOptions::default()
+        };
+        LlvmJit::parse_and_act_with_options(
+            code.to_owned(),
+            super::Action::EmitObject(object.clone()),
+            &options,
+        );
+
+        let bytes = std::fs::read(&object).unwrap();
+        std::fs::remove_file(&object).unwrap();
+        let contains = |needle: &[u8]| bytes.windows(needle.len()).any(|x| x == needle);
+        assert!(contains(b".debug_line"));
+        assert!(contains(b".debug_info"));
+        assert!(contains(b"hello_world.bf"));
+    }
+
+    #[test]
+    fn perf_map_regions() {
+        let prog = Parser::parse_to_bytecode(String::from("++[>++[>+<-]<-]>>."));
+        let options = JitOptions {
+            debug_info: true,
+            opt_level: Some(0),
+            ..JitOptions::default()
+        };
+        let compiler = LlvmJit::for_host();
+        let mut compilation = super::Compilation::new(&options);
+        let object = compiler.compile_object(&prog, &options, &mut compilation);
+        let size = LlvmJit::object_function_size(&object).unwrap();
+
+        let regions = LlvmJit::perf_map_regions(&object, &prog, size);
+        let names: Vec<_> = regions.iter().map(|region| region.name.as_str()).collect();
+        assert!(names.contains(&"bf_program"));
+        assert!(names.contains(&"bf_loop@1:3-1:15"));
+        assert!(names.contains(&"bf_loop@1:7-1:12"));
+        assert!(regions.windows(2).all(|pair| pair[0].end <= pair[1].start));
+        assert!(regions.last().unwrap().end <= size);
+    }
+
+    #[test]
+    fn caller_owned_tape() {
+        inkwell::targets::Target::initialize_native(&Default::default()).unwrap();
+        let compiler = LlvmJit {
+            context: Context::create(),
+        };
+        let mut prog = Parser::parse_to_bytecode(String::from("+++>++[->+<]"));
+        prog.opt_pass_1();
+        let mut tape = [0; 4];
+        let mut io = Io::new(std::io::empty(), std::io::sink(), EofPolicy::default());
+        let result = compiler.execute(&prog, &mut tape, &mut io, &JitOptions::default());
+        assert_eq!(
+            result,
+            JitResult {
+                dataptr: 1,
+                status: STATUS_OK
+            }
+        );
+        assert_eq!(tape, [3, 0, 2, 0]);
+
+        let prog = Parser::parse_to_bytecode(String::from(">>[-]<<<"));
+        let mut tape = [0; 4];
+        let result = compiler.execute(&prog, &mut tape, &mut io, &JitOptions::default());
+        assert_eq!(result.status, STATUS_OUT_OF_BOUNDS);
+        assert_eq!(result.dataptr, -1);
+
+        let prog = Parser::parse_to_bytecode(String::from(">>>>"));
+        let result = compiler.execute(&prog, &mut tape, &mut io, &JitOptions::default());
+        assert_eq!(
+            result,
+            JitResult {
+                dataptr: 4,
+                status: STATUS_OUT_OF_BOUNDS
+            }
+        );
+    }
+
+    #[test]
+    fn optimized_patterns() {
+        inkwell::targets::Target::initialize_native(&Default::default()).unwrap();
+        let compiler = LlvmJit {
+            context: Context::create(),
+        };
+        let run = |code: &str, tape: &mut [u8]| {
+            let mut prog = Parser::parse_to_bytecode(String::from(code));
+            prog.opt_pass_1();
+            prog.opt_pass_2();
+            let mut io = Io::new(std::io::empty(), std::io::sink(), EofPolicy::default());
+            compiler.execute(&prog, tape, &mut io, &JitOptions::default())
+        };
+        let ok = |dataptr| JitResult {
+            dataptr,
+            status: STATUS_OK,
+        };
+        let out_of_bounds = |dataptr| JitResult {
+            dataptr,
+            status: STATUS_OUT_OF_BOUNDS,
+        };
+
+        // multiply loops
+        let mut tape = [0; 4];
+        assert_eq!(run("+++[->++>+++<<]", &mut tape), ok(0));
+        assert_eq!(tape, [0, 6, 9, 0]);
+        let mut tape = [1];
+        assert_eq!(run("[->+<]", &mut tape), out_of_bounds(1));
+        let mut tape = [0];
+        assert_eq!(run("[->+<]", &mut tape), ok(0));
+
+        // scans
+        let mut tape = [0; 5];
+        assert_eq!(run("+>+>+>+<<<[>]", &mut tape), ok(4));
+        let mut tape = [0; 4];
+        assert_eq!(run("+>+>+>+<<<[>]", &mut tape), out_of_bounds(4));
+        let mut tape = [0, 1, 0, 1, 1];
+        assert_eq!(run(">>>>[<<]", &mut tape), ok(2));
+
+        // runs of clears
+        let mut tape = [5; 4];
+        assert_eq!(run("[-]>[-]>[-]", &mut tape), ok(2));
+        assert_eq!(tape, [0, 0, 0, 5]);
+        let mut tape = [5; 2];
+        assert_eq!(run("[-]>[-]>[-]", &mut tape), out_of_bounds(2));
+        assert_eq!(tape, [0, 0]);
+    }
+
+    #[test]
+    fn code_cache() {
+        inkwell::targets::Target::initialize_native(&Default::default()).unwrap();
+        let dir = std::env::temp_dir().join(format!("bf-llvm-cache-{}", std::process::id()));
+        let cache = CodeCache::new(&dir, CodeCache::DEFAULT_MAX_SIZE);
+        cache.clear().unwrap();
+        let options = JitOptions {
+            code_cache: Some(cache.clone()),
+            ..Default::default()
+        };
+        let prog = Parser::parse_to_bytecode(String::from(",[.-]"));
+        // Compiled and cached the first time, loaded from the cache after.
+        for _ in 0..2 {
+            let compiler = LlvmJit {
+                context: Context::create(),
+            };
+            let mut out = vec![];
+            let mut io = Io::new(b"\x03".as_slice(), &mut out, EofPolicy::default());
+            let mut tape = [0; 1];
+            let result = compiler.execute(&prog, &mut tape, &mut io, &options);
+            io.finish().unwrap();
+            assert_eq!(result.status, STATUS_OK);
+            assert_eq!(out, [3, 2, 1]);
+            assert_eq!(cache.stats().unwrap().0, 1);
+        }
+
+        let unoptimized = JitOptions {
+            opt_level: Some(0),
+            ..options.clone()
+        };
+        let compiler = LlvmJit {
+            context: Context::create(),
+        };
+        let mut io = Io::new(std::io::empty(), std::io::sink(), EofPolicy::default());
+        compiler.execute(&prog, &mut [0; 1], &mut io, &unoptimized);
+        assert_eq!(cache.stats().unwrap().0, 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn runtime_io() {
+        inkwell::targets::Target::initialize_native(&Default::default()).unwrap();
+        let compiler = LlvmJit {
+            context: Context::create(),
+        };
+        let run = |src: &str, input: &[u8], eof_policy| {
+            let prog = Parser::parse_to_bytecode(String::from(src));
+            let mut out = vec![];
+            let mut io = Io::new(input, &mut out, eof_policy);
+            let mut tape = [0; 16];
+            let result = compiler.execute(&prog, &mut tape, &mut io, &JitOptions::default());
+            assert_eq!(result.status, STATUS_OK);
+            io.finish().unwrap();
+            out
+        };
+        assert_eq!(run(",[.,]", b"echo", EofPolicy::Zero), b"echo");
+        assert_eq!(run("+++++,.", b"", EofPolicy::Zero), [0]);
+        assert_eq!(run("+++++,.", b"", EofPolicy::AllOnes), [255]);
+        assert_eq!(run("+++++,.", b"", EofPolicy::Unchanged), [5]);
+    }
+
     #[test]
     fn hello_world() {
         let code = include_str!("../programs/hello_world.bf");
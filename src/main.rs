@@ -1,6 +1,405 @@
-use bf_interpreter;
+use std::{
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+use bf_interpreter::{
+    asm_backend::{AsmEmitter, AsmOptions, AsmSyntax},
+    bench,
+    c_backend::{COptions, CTranspiler, CellWidth},
+    code_cache::CodeCache,
+    cranelift_jit::CraneliftJit,
+    io::EofPolicy,
+    jit_utils::JitOptions,
+    llvm_jit::{Action, LlvmJit},
+    optbytecode_jit::BytecodeJit,
+    parser::Parser,
+    profiler::LoopProfiler,
+    simple_jit::SimpleJit,
+    threaded::ThreadedInterpreter,
+    tiered::TieredJit,
+    wasm_backend::WasmEmitter,
+};
+
+const USAGE: &str = "usage: main [--backend <name>] [--coverage <out.info>] [--profile <out.folded>] [--perf-map] [--gdb] [--dump-asm] [--emit <kind>] [-o <output>] [-O<0-3>] [--passes <pipeline>] [--time-stages] [-g] [--eof <policy>] [--cell-width <bits>] [--asm-syntax <syntax>] [--cache] [--cache-dir <dir>] [--cache-size <MiB>] <program.bf>
+       main --backend llvm --emit so -o <lib.so> [--header <lib.h>] <program.bf>...
+       main cache stats|clear [--cache-dir <dir>]
+       main bench [--runs <n>] [--json] [--backend <name>]... <program.bf>...
+
+backends: interpreter (default), bytecode, threaded, simple-jit, bytecode-jit, tiered, cranelift, llvm, c, wasm, asm
+threaded   interprets the optimized bytecode as a table of handlers, without a JIT
+tiered     interprets the bytecode, and runs loops through bytecode-jit once they are hot
+c          prints the program as C source, or writes it to the -o file
+asm        prints the bytecode-jit code as a GNU assembler file defining main, or writes it to the -o file
+wasm       writes a WebAssembly module to the -o file, importing env.read and env.write and exporting run and memory
+--coverage writes an lcov report (interpreter and bytecode backends only)
+--profile  writes loop nesting samples in folded-stack format (interpreter and bytecode backends only)
+--perf-map writes /tmp/perf-<pid>.map symbols for the generated code (JIT backends only)
+--gdb      registers the generated code with gdb's JIT interface (simple-jit and bytecode-jit only)
+--dump-asm prints the annotated machine code instead of running it (simple-jit, bytecode-jit and cranelift only)
+--emit     llvm only: llvm-ir (printed to stdout), llvm-bc, asm, obj, exe (default with -o) or so
+           simple-jit can only emit exe, a static Linux executable built without LLVM or a linker
+           so compiles each program into a bf_<file name> function of a shared library
+--header   where --emit so writes the C header for the library, <lib>.h by default
+-o         writes the --emit output to a file instead of running the program (llvm, simple-jit, c, wasm and asm only)
+-O<level>  llvm optimization level, 3 by default
+--passes   llvm pass pipeline in `opt -passes` syntax, default<O<level>> by default
+--time-stages prints how long each llvm or cranelift compilation stage took
+-g         adds DWARF debug info for the .bf source to llvm output
+--eof      what ',' stores at the end of input: zero, 255 or unchanged (default, threaded, tiered, llvm, cranelift, c, wasm and asm only)
+--cell-width bits per cell in C: 8 (default), 16 or 32
+--asm-syntax att (default) or intel
+--cache    reuses llvm object code compiled by earlier runs, kept in $BF_CACHE_DIR or ~/.cache/bf_interpreter
+--cache-dir keeps the --cache in <dir> instead, implies --cache
+--cache-size evicts least recently used code once the cache is larger than <MiB>, 64 by default
+cache stats prints the number and total size of cached programs, cache clear removes them
+bench      runs each program on every backend that can run it in-process, or each --backend given,
+           --runs times (3 by default), and prints the median parse, optimization, compilation and
+           execution times and the code size as a table, or as JSON with --json. Programs get no input.
+           bytecode-jit-io runs the bytecode-jit code that calls into the runtime for I/O, as tiered
+           does, instead of the code bytecode-jit runs.";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(1);
+}
+
+fn read_source(path: &str) -> String {
+    fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Failed to read {}: {}", path, e);
+        exit(1);
+    })
+}
+
+fn create_file(path: &str) -> fs::File {
+    fs::File::create(path).unwrap_or_else(|e| {
+        eprintln!("Failed to create {}: {}", path, e);
+        exit(1);
+    })
+}
+
+/// `main cache stats|clear [--cache-dir <dir>]`
+fn cache_command(mut args: impl Iterator<Item = String>) {
+    let command = args.next().unwrap_or_else(|| usage());
+    let mut dir = CodeCache::default_dir();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cache-dir" => dir = PathBuf::from(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+    let cache = CodeCache::new(dir, CodeCache::DEFAULT_MAX_SIZE);
+    let result = match command.as_str() {
+        "stats" => cache.stats().map(|(count, size)| {
+            println!(
+                "{}: {} programs, {:.1} KiB",
+                cache.dir().display(),
+                count,
+                size as f64 / 1024.0
+            )
+        }),
+        "clear" => cache
+            .clear()
+            .map(|count| println!("Removed {} programs from {}", count, cache.dir().display())),
+        _ => usage(),
+    };
+    result.unwrap_or_else(|e| {
+        eprintln!("Failed to access {}: {}", cache.dir().display(), e);
+        exit(1);
+    });
+}
+
+/// `main bench [--runs <n>] [--json] [--backend <name>]... <program.bf>...`
+fn bench_command(mut args: impl Iterator<Item = String>) {
+    let mut runs = 3;
+    let mut json = false;
+    let mut backends = vec![];
+    let mut paths = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--runs" => {
+                runs = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n > 0)
+                    .unwrap_or_else(|| usage())
+            }
+            "--json" => json = true,
+            "--backend" => {
+                let name = args.next().unwrap_or_else(|| usage());
+                let backend = bench::backends()
+                    .into_iter()
+                    .find(|backend| *backend == name)
+                    .unwrap_or_else(|| usage());
+                backends.push(backend);
+            }
+            _ if arg.starts_with('-') => usage(),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        usage();
+    }
+    if backends.is_empty() {
+        backends = bench::backends();
+    }
+    let mut measurements = vec![];
+    for path in &paths {
+        let src = read_source(path);
+        for backend in &backends {
+            measurements.push(bench::measure(path, &src, backend, runs));
+        }
+    }
+    if json {
+        print!("{}", bench::json(&measurements));
+    } else {
+        print!("{}", bench::table(&measurements));
+    }
+}
+
 fn main() {
-    // let prog = bf_interpreter::bf::Program::parse(src_code);
-    // bf_interpreter:
-    // bf_interpreter::
+    let mut args = env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("cache") => {
+            args.next();
+            cache_command(args);
+            return;
+        }
+        Some("bench") => {
+            args.next();
+            bench_command(args);
+            return;
+        }
+        _ => {}
+    }
+    let mut backend = String::from("interpreter");
+    let mut coverage_out = None;
+    let mut profile_out = None;
+    let mut jit_options = JitOptions::default();
+    let mut c_options = COptions::default();
+    let mut asm_options = AsmOptions::default();
+    let mut emit = None;
+    let mut output = None;
+    let mut header = None;
+    let mut paths = vec![];
+    let mut cache_dir = None;
+    let mut cache_size = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--backend" => backend = args.next().unwrap_or_else(|| usage()),
+            "--coverage" => coverage_out = Some(args.next().unwrap_or_else(|| usage())),
+            "--profile" => profile_out = Some(args.next().unwrap_or_else(|| usage())),
+            "--perf-map" => jit_options.perf_map = true,
+            "--gdb" => jit_options.gdb = true,
+            "--dump-asm" => jit_options.dump_asm = true,
+            "--emit" => emit = Some(args.next().unwrap_or_else(|| usage())),
+            "-O0" | "-O1" | "-O2" | "-O3" => jit_options.opt_level = arg[2..].parse().ok(),
+            "--passes" => jit_options.passes = Some(args.next().unwrap_or_else(|| usage())),
+            "--time-stages" => jit_options.time_stages = true,
+            "-g" => jit_options.debug_info = true,
+            "--eof" => {
+                jit_options.eof_policy = match args.next().as_deref() {
+                    Some("zero") => EofPolicy::Zero,
+                    Some("255") => EofPolicy::AllOnes,
+                    Some("unchanged") => EofPolicy::Unchanged,
+                    _ => usage(),
+                }
+            }
+            "--cache" => {
+                cache_dir.get_or_insert_with(CodeCache::default_dir);
+            }
+            "--cache-dir" => {
+                cache_dir = Some(PathBuf::from(args.next().unwrap_or_else(|| usage())))
+            }
+            "--cache-size" => {
+                let mib: u64 = args
+                    .next()
+                    .and_then(|size| size.parse().ok())
+                    .unwrap_or_else(|| usage());
+                cache_size = Some(mib << 20);
+            }
+            "--asm-syntax" => {
+                asm_options.syntax = match args.next().as_deref() {
+                    Some("att") => AsmSyntax::Att,
+                    Some("intel") => AsmSyntax::Intel,
+                    _ => usage(),
+                }
+            }
+            "--cell-width" => {
+                c_options.cell_width = match args.next().as_deref() {
+                    Some("8") => CellWidth::U8,
+                    Some("16") => CellWidth::U16,
+                    Some("32") => CellWidth::U32,
+                    _ => usage(),
+                }
+            }
+            "-o" => output = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            "--header" => header = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            "-h" | "--help" => usage(),
+            _ => paths.push(arg),
+        }
+    }
+    jit_options.code_cache =
+        cache_dir.map(|dir| CodeCache::new(dir, cache_size.unwrap_or(CodeCache::DEFAULT_MAX_SIZE)));
+
+    if emit.as_deref() == Some("so") {
+        if backend != "llvm" {
+            eprintln!("--emit so is only supported by the llvm backend");
+            exit(1);
+        }
+        let output = output.unwrap_or_else(|| usage());
+        let header = header.unwrap_or_else(|| output.with_extension("h"));
+        if paths.is_empty() {
+            usage();
+        }
+        let sources = paths
+            .into_iter()
+            .map(|path| {
+                let src = read_source(&path);
+                (path, src)
+            })
+            .collect();
+        LlvmJit::parse_and_build_shared_library(sources, &output, &header, &jit_options);
+        return;
+    }
+
+    let path = match paths.as_slice() {
+        [path] => path.clone(),
+        _ => usage(),
+    };
+    jit_options.source_name = Some(path.clone());
+    let src = read_source(&path);
+
+    if coverage_out.is_some() || profile_out.is_some() {
+        if coverage_out.is_some() && profile_out.is_some() {
+            eprintln!("--coverage and --profile can't be used together");
+            exit(1);
+        }
+        let root = Path::new(&path)
+            .file_name()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.clone());
+        match backend.as_str() {
+            "interpreter" => {
+                let prog = Parser::parse(src);
+                if let Some(out) = coverage_out {
+                    let coverage = prog.eval_with_coverage();
+                    coverage
+                        .write_lcov(&path, &mut create_file(&out))
+                        .expect("Failed to write coverage report");
+                } else if let Some(out) = profile_out {
+                    let mut profiler = LoopProfiler::for_program(&prog);
+                    prog.eval_with_observer(&mut profiler);
+                    profiler
+                        .write_folded(&root, &mut create_file(&out))
+                        .expect("Failed to write profile");
+                }
+            }
+            "bytecode" => {
+                // Not optimized, so that every loop keeps its brackets.
+                let prog = Parser::parse_to_bytecode(src);
+                if let Some(out) = coverage_out {
+                    let coverage = prog.eval_with_coverage();
+                    coverage
+                        .write_lcov(&path, &mut create_file(&out))
+                        .expect("Failed to write coverage report");
+                } else if let Some(out) = profile_out {
+                    let mut profiler = LoopProfiler::for_bytecode(&prog);
+                    prog.eval_with_observer(&mut profiler);
+                    profiler
+                        .write_folded(&root, &mut create_file(&out))
+                        .expect("Failed to write profile");
+                }
+            }
+            _ => {
+                eprintln!(
+                    "--coverage and --profile are only supported by the interpreter and bytecode backends"
+                );
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    if backend == "c" || backend == "asm" {
+        if emit.is_some() {
+            usage();
+        }
+        let source = if backend == "c" {
+            CTranspiler::parse_and_transpile(src, &jit_options, &c_options)
+        } else {
+            AsmEmitter::parse_and_emit(src, &jit_options, &asm_options)
+        };
+        match output {
+            Some(output) => fs::write(&output, source).unwrap_or_else(|e| {
+                eprintln!("Failed to write {}: {}", output.display(), e);
+                exit(1);
+            }),
+            None => print!("{}", source),
+        }
+        return;
+    }
+
+    if backend == "wasm" {
+        let output = match (emit, output) {
+            (None, Some(output)) => output,
+            _ => usage(),
+        };
+        let wasm = WasmEmitter::parse_and_emit(src, &jit_options);
+        fs::write(&output, wasm).unwrap_or_else(|e| {
+            eprintln!("Failed to write {}: {}", output.display(), e);
+            exit(1);
+        });
+        return;
+    }
+
+    if backend == "simple-jit" && (emit.is_some() || output.is_some()) {
+        let output = match (emit.as_deref().unwrap_or("exe"), output) {
+            ("exe", Some(output)) => output,
+            _ => usage(),
+        };
+        let exe = SimpleJit::parse_and_build_executable(src);
+        fs::write(&output, exe)
+            .and_then(|()| fs::set_permissions(&output, fs::Permissions::from_mode(0o755)))
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to write {}: {}", output.display(), e);
+                exit(1);
+            });
+        return;
+    }
+
+    if emit.is_some() || output.is_some() {
+        if backend != "llvm" {
+            eprintln!("--emit and -o are only supported by the llvm backend");
+            exit(1);
+        }
+        let action = match (emit.as_deref().unwrap_or("exe"), output) {
+            ("llvm-ir", None) => Action::Print,
+            ("llvm-bc", Some(output)) => Action::EmitBitcode(output),
+            ("asm", Some(output)) => Action::EmitAssembly(output),
+            ("obj", Some(output)) => Action::EmitObject(output),
+            ("exe", Some(output)) => Action::BuildExecutable(output),
+            _ => usage(),
+        };
+        LlvmJit::parse_and_act_with_options(src, action, &jit_options);
+        return;
+    }
+
+    match backend.as_str() {
+        "interpreter" => Parser::parse(src).eval(),
+        "bytecode" => {
+            let mut prog = Parser::parse_to_bytecode(src);
+            prog.opt_pass_1();
+            prog.eval();
+        }
+        "threaded" => ThreadedInterpreter::parse_and_run_with_options(src, &jit_options),
+        "simple-jit" => SimpleJit::parse_and_run_with_options(src, &jit_options),
+        "bytecode-jit" => BytecodeJit::parse_and_run_with_options(src, &jit_options),
+        "llvm" => LlvmJit::parse_and_act_with_options(src, Action::Execute, &jit_options),
+        "cranelift" => CraneliftJit::parse_and_run_with_options(src, &jit_options),
+        "tiered" => TieredJit::parse_and_run_with_options(src, &jit_options),
+        _ => usage(),
+    }
 }
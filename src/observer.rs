@@ -0,0 +1,15 @@
+/// Hooks the interpreters call while executing a program.
+///
+/// `pc` is the index of the instruction in the program being run, so an
+/// observer can map it back to source through `Program::locations` or
+/// `ByteCodeProgram::spans`. The unit type is the no-op observer used by the
+/// plain `eval` entry points.
+pub trait ExecutionObserver {
+    /// Called before every executed instruction.
+    fn instruction(&mut self, _pc: usize) {}
+
+    /// Called after a `[` or `]` decided whether to jump.
+    fn branch(&mut self, _pc: usize, _taken: bool) {}
+}
+
+impl ExecutionObserver for () {}
@@ -1,11 +1,18 @@
+use std::io::{Read, Write};
 use std::mem::transmute_copy;
+use std::ops::Range;
 
-use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi};
+use dynasmrt::x64::Assembler;
+use dynasmrt::{dynasm, AssemblyOffset, DynamicLabel, DynasmApi, DynasmLabelApi, ExecutableBuffer};
 
 use crate::{
-    bytecode_bf::{ByteCode, Change},
-    parser::Parser,
-    MEMORY_SIZE,
+    bytecode_bf::{ByteCode, ByteCodeProgram, Change},
+    disasm,
+    gdb_jit::{self, GdbRegistration},
+    io,
+    jit_utils::{JitOptions, JitResult, LoopRegions, STATUS_OK, STATUS_OUT_OF_BOUNDS},
+    parser::{Parser, SourceLoc},
+    perf_map, MEMORY_SIZE,
 };
 
 macro_rules! my_dynasm {
@@ -21,47 +28,233 @@ pub struct BytecodeJit {}
 
 impl BytecodeJit {
     pub fn parse_and_run(src: String) {
+        Self::parse_and_run_with_options(src, &JitOptions::default());
+    }
+
+    pub fn parse_and_run_with_options(src: String, options: &JitOptions) {
         let prog = Parser::parse_to_bytecode(src);
-        let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+        let mut ops = Assembler::new().unwrap();
         let mut memory = vec![0 as u8; MEMORY_SIZE];
         let x = memory.as_mut_ptr();
 
-        let mut open_bracket_stack = vec![];
         let start = ops.offset();
+        let mut regions = LoopRegions::new(start.0);
+        // (code offset, source location) of every instruction for gdb
+        let mut line_rows = vec![];
 
         my_dynasm!(ops
         ;mov r13, QWORD x as _
         );
+        Self::emit_instructions(
+            &mut ops,
+            &prog,
+            0..prog.instructions.len(),
+            IoCalls::Syscalls,
+            None,
+            &mut regions,
+            &mut line_rows,
+        );
+        let ret_offset = ops.offset();
+        my_dynasm!(ops
+        ;ret
+        );
+        let end = ops.offset();
+        let regions = regions.finish(end.0);
+
+        let cmt = ops.commit();
+        if cmt.is_err() {
+            println!("{:?}", cmt.err());
+            return;
+        }
+
+        let mut annotations = vec![];
+        if options.dump_asm {
+            annotations.push((0, String::from("prologue")));
+            for (pc, (offset, _)) in line_rows.iter().enumerate() {
+                let span = prog.spans[pc];
+                annotations.push((
+                    *offset - start.0,
+                    format!(
+                        "{:?} at {}:{}-{}:{}",
+                        prog.instructions[pc],
+                        span.start.line,
+                        span.start.col,
+                        span.end.line,
+                        span.end.col
+                    ),
+                ));
+            }
+            annotations.push((ret_offset.0 - start.0, String::from("epilogue")));
+        }
+
+        let code = ops.finalize();
+        match code {
+            Ok(prog) if options.dump_asm => {
+                print!(
+                    "{}",
+                    disasm::disassemble(&prog[start.0..end.0], &annotations)
+                );
+                return;
+            }
+            Ok(prog) => unsafe {
+                if options.perf_map {
+                    perf_map::append_to_perf_map(prog.ptr(start) as usize - start.0, &regions)
+                        .expect("Failed to write perf map");
+                }
+                let _gdb_registration = options.gdb.then(|| {
+                    GdbRegistration::register(gdb_jit::symbol_file(
+                        options.source_name(),
+                        prog.ptr(start) as usize - start.0,
+                        end.0,
+                        &regions,
+                        &line_rows,
+                    ))
+                });
+                let jit_fn: unsafe extern "C" fn() -> () = transmute_copy(&prog.ptr(start));
+                jit_fn();
+            },
+            Err(e) => println!("{:?}", e),
+        }
+        // let code = ops.finalize().unwrap();
+        // unsafe {}
+        println!("");
+    }
 
-        for (pc, instr) in prog.instructions.iter().enumerate() {
+    /// Compiles the loop from the `JZ` at `jz` to its `JNZ` at `jnz` into
+    /// `JitResult loop(u8 *tape, i64 len, i64 dataptr, Io<R, W> *io)`,
+    /// which runs it with the data pointer at `dataptr` and returns where
+    /// the pointer ends up, or where it left the tape. `.` and `,` call
+    /// `io::bf_write` and `io::bf_read`, so the loop shares its buffers
+    /// with whoever runs the rest of the program.
+    pub fn compile_loop<R: Read, W: Write>(
+        prog: &ByteCodeProgram,
+        jz: usize,
+        jnz: usize,
+    ) -> CompiledCode {
+        Self::compile_range::<R, W>(prog, jz..jnz + 1)
+    }
+
+    /// Compiles all of `prog` the way `compile_loop` compiles a loop.
+    pub fn compile_program<R: Read, W: Write>(prog: &ByteCodeProgram) -> CompiledCode {
+        Self::compile_range::<R, W>(prog, 0..prog.instructions.len())
+    }
+
+    fn compile_range<R: Read, W: Write>(
+        prog: &ByteCodeProgram,
+        range: Range<usize>,
+    ) -> CompiledCode {
+        let mut ops = Assembler::new().unwrap();
+        let entry = ops.offset();
+        let out_of_bounds = ops.new_dynamic_label();
+        // r12 holds io, r14 the tape and r15 its length. All of them are
+        // callee-saved, and the extra 8 bytes keep the stack aligned for the
+        // calls.
+        my_dynasm!(ops
+        ; push r13
+        ; push r12
+        ; push r14
+        ; push r15
+        ; sub rsp, 8
+        ; mov r14, rdi
+        ; mov r15, rsi
+        ; lea a_current, [rdi + rdx]
+        ; mov r12, rcx
+        );
+        // an empty tape doesn't even have a current cell
+        Self::emit_bounds_check(&mut ops, Some(out_of_bounds));
+        let io = IoCalls::Runtime {
+            write: io::bf_write::<R, W> as *const () as i64,
+            read: io::bf_read::<R, W> as *const () as i64,
+        };
+        Self::emit_instructions(
+            &mut ops,
+            prog,
+            range,
+            io,
+            Some(out_of_bounds),
+            &mut LoopRegions::new(entry.0),
+            &mut vec![],
+        );
+        // Returns the index of the current cell and the status in rax:rdx,
+        // as a `JitResult`.
+        my_dynasm!(ops
+        ; mov edx, STATUS_OK
+        ; jmp >exit
+        ; =>out_of_bounds
+        ; mov edx, STATUS_OUT_OF_BOUNDS
+        ; exit:
+        ; mov rax, a_current
+        ; sub rax, r14
+        ; add rsp, 8
+        ; pop r15
+        ; pop r14
+        ; pop r12
+        ; pop r13
+        ; ret
+        );
+        CompiledCode {
+            code: ops.finalize().unwrap(),
+            entry,
+        }
+    }
+
+    /// Jumps to `out_of_bounds`, if there is one, unless r13 points into
+    /// the tape at r14, which is r15 cells long. Pointers before the tape
+    /// wrap around, so one unsigned comparison covers both ends.
+    fn emit_bounds_check(ops: &mut Assembler, out_of_bounds: Option<DynamicLabel>) {
+        if let Some(out_of_bounds) = out_of_bounds {
+            my_dynasm!(ops
+            ; mov rax, a_current
+            ; sub rax, r14
+            ; cmp rax, r15
+            ; jae =>out_of_bounds
+            );
+        }
+    }
+
+    /// Emits the instructions in `range`, whose brackets must match,
+    /// working on the cell r13 points at. With `out_of_bounds`, the code
+    /// checks the data pointer against the tape in r14 and r15 whenever it
+    /// moves, and jumps there with it when it leaves.
+    fn emit_instructions(
+        ops: &mut Assembler,
+        prog: &ByteCodeProgram,
+        range: Range<usize>,
+        io: IoCalls,
+        out_of_bounds: Option<DynamicLabel>,
+        regions: &mut LoopRegions,
+        line_rows: &mut Vec<(usize, SourceLoc)>,
+    ) {
+        let mut open_bracket_stack = vec![];
+        for pc in range {
+            let instr = &prog.instructions[pc];
+            line_rows.push((ops.offset().0, prog.spans[pc].start));
             match instr {
                 ByteCode::DataPointerIncr(delta) => {
                     my_dynasm!(ops
                     ; add a_current , *delta as _
                     );
+                    Self::emit_bounds_check(ops, out_of_bounds);
                 }
                 ByteCode::DataPointerDecr(delta) => {
                     my_dynasm!(ops
                     ; sub a_current, *delta as _
                     );
+                    Self::emit_bounds_check(ops, out_of_bounds);
                 }
+                // Cells wrap around, and so does truncating delta to a byte.
                 ByteCode::DataIncr(delta) => {
-                    if *delta > u8::MAX as usize {
-                        panic!("Overflow");
-                    }
                     my_dynasm!(ops
                     ; add BYTE [a_current + 0], *delta as _
                     );
                 }
                 ByteCode::DataDecr(delta) => {
-                    if *delta > u8::MAX as usize {
-                        panic!("Overflow");
-                    }
                     my_dynasm!(ops
                     ; sub BYTE [a_current + 0], *delta as _
                     );
                 }
                 ByteCode::JZ => {
+                    regions.enter_loop(ops.offset().0, prog.spans[pc].start);
                     my_dynasm!(ops
                     ; cmp BYTE [a_current + 0] , 0
                     );
@@ -83,6 +276,7 @@ impl BytecodeJit {
                     ; jnz => open_label
                     ; => close_label
                     );
+                    regions.leave_loop(ops.offset().0, prog.spans[pc].end);
                 }
                 ByteCode::SETZERO => {
                     my_dynasm!(ops
@@ -101,74 +295,139 @@ impl BytecodeJit {
                     match chng {
                         Change::Incr(x) => {
                             my_dynasm!(ops
-                                    ; add BYTE [a_current + 0] , *x as _
-                                    ; jmp =>start_loop // (should have this??)
+                                    ; add a_current, *x as _
                             );
                         }
                         Change::Decr(x) => {
                             my_dynasm!(ops
-                                    ; sub BYTE [a_current + 0] , *x as _
-                                    ; jmp =>start_loop // (should jump back too?)
+                                    ; sub a_current, *x as _
                             );
                         }
                     }
+                    Self::emit_bounds_check(ops, out_of_bounds);
 
                     my_dynasm!(ops
+                        ; jmp =>start_loop
                         ; => end_loop);
                 }
-                ByteCode::Write => {
-                    // mov $1, %rax
-                    // mov $1, %rdi
-                    // mov %r13, %rsi
-                    // mov $1, %rdx
-                    // syscall
-                    dynasm!(ops
-                    ; mov rax , 1
-                    ; mov rdi , 1
-                    ; mov rsi, r13
-                    ; mov rdx, 1
-                    ; syscall
+                ByteCode::Write => match io {
+                    IoCalls::Syscalls => {
+                        // mov $1, %rax
+                        // mov $1, %rdi
+                        // mov %r13, %rsi
+                        // mov $1, %rdx
+                        // syscall
+                        dynasm!(ops
+                        ; mov rax , 1
+                        ; mov rdi , 1
+                        ; mov rsi, r13
+                        ; mov rdx, 1
+                        ; syscall
+                        );
+                    }
+                    IoCalls::Runtime { write, .. } => {
+                        // bf_write(io, cell)
+                        my_dynasm!(ops
+                        ; mov rdi, r12
+                        ; movzx esi, BYTE [a_current + 0]
+                        ; mov rax, QWORD write
+                        ; call rax
+                        );
+                    }
+                },
+                ByteCode::Read => match io {
+                    IoCalls::Syscalls => {
+                        // mov $0, %rax
+                        // mov $0, %rdi
+                        // mov %r13, %rsi
+                        // mov $1, %rdx
+                        // syscall
+                        dynasm!(ops
+                        ; mov rax , 0
+                        ; mov rdi , 0
+                        ; mov rsi, r13
+                        ; mov rdx, 1
+                        ; syscall
+                        );
+                    }
+                    IoCalls::Runtime { read, .. } => {
+                        // cell = bf_read(io, cell)
+                        my_dynasm!(ops
+                        ; mov rdi, r12
+                        ; movzx esi, BYTE [a_current + 0]
+                        ; mov rax, QWORD read
+                        ; call rax
+                        ; mov BYTE [a_current + 0], al
+                        );
+                    }
+                },
+                ByteCode::MultiplyAdd(offset, factor) => {
+                    // the loop it replaces doesn't touch the other cell if
+                    // this one is 0
+                    my_dynasm!(ops
+                    ; movzx eax, BYTE [a_current + 0]
+                    ; test eax, eax
+                    ; jz >skip
                     );
-                }
-                ByteCode::Read => {
-                    // mov $0, %rax
-                    // mov $0, %rdi
-                    // mov %r13, %rsi
-                    // mov $1, %rdx
-                    // syscall
-                    dynasm!(ops
-                    ; mov rax , 0
-                    ; mov rdi , 0
-                    ; mov rsi, r13
-                    ; mov rdx, 1
-                    ; syscall
+                    // Only a loop that runs at all moves the pointer to the
+                    // target, so going off the tape is reported at the target.
+                    if let Some(out_of_bounds) = out_of_bounds {
+                        my_dynasm!(ops
+                        ; lea rcx, [a_current + *offset as _]
+                        ; sub rcx, r14
+                        ; cmp rcx, r15
+                        ; jb >in_bounds
+                        ; add a_current, *offset as _
+                        ; jmp =>out_of_bounds
+                        ; in_bounds:
+                        );
+                    }
+                    my_dynasm!(ops
+                    ; imul eax, eax, *factor as _
+                    ; add BYTE [a_current + *offset as _], al
+                    ; skip:
                     );
                 }
                 ByteCode::Nop => {}
-                _ => unimplemented!(),
             }
         }
-        my_dynasm!(ops
-        ;ret
-        );
+    }
+}
 
-        let cmt = ops.commit();
-        if cmt.is_err() {
-            println!("{:?}", cmt.err());
-            return;
-        }
+/// How compiled code does `.` and `,`.
+#[derive(Clone, Copy)]
+enum IoCalls {
+    /// `write` and `read` syscalls on stdout and stdin.
+    Syscalls,
+    /// Calls to `io::bf_write` and `io::bf_read` at these addresses, with
+    /// the `Io` in r12.
+    Runtime { write: i64, read: i64 },
+}
 
-        let code = ops.finalize();
-        match code {
-            Ok(prog) => unsafe {
-                let jit_fn: unsafe extern "C" fn() -> () = transmute_copy(&prog.ptr(start));
-                jit_fn();
-            },
-            Err(e) => println!("{:?}", e),
-        }
-        // let code = ops.finalize().unwrap();
-        // unsafe {}
-        println!("");
+/// A loop or program `BytecodeJit` compiled to run with an `Io`.
+pub struct CompiledCode {
+    code: ExecutableBuffer,
+    entry: AssemblyOffset,
+}
+
+impl CompiledCode {
+    /// Size of the machine code in bytes.
+    pub fn size(&self) -> usize {
+        self.code.len()
+    }
+
+    /// Runs the code on `tape` from the cell at `dataptr`. Unlike the
+    /// rest of the JIT, it stops with `STATUS_OUT_OF_BOUNDS` when the data
+    /// pointer leaves the tape.
+    ///
+    /// # Safety
+    ///
+    /// `io` must point to a live `Io<R, W>` of the types the code was
+    /// compiled for.
+    pub unsafe fn run(&self, tape: &mut [u8], dataptr: usize, io: *mut u8) -> JitResult {
+        let loop_fn: unsafe extern "C" fn(*mut u8, i64, i64, *mut u8) -> JitResult =
+            transmute_copy(&self.code.ptr(self.entry));
+        loop_fn(tape.as_mut_ptr(), tape.len() as i64, dataptr as i64, io)
     }
 }
 
@@ -3,16 +3,50 @@ use crate::{
     bytecode_bf::{ByteCode, ByteCodeProgram},
 };
 
+/// 1-based line/column of a character in the `.bf` source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct SourceLoc {
+    pub line: usize,
+    pub col: usize,
+}
+
+/// Source range covered by one instruction, both ends inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: SourceLoc,
+    pub end: SourceLoc,
+}
+
+impl Span {
+    pub fn merge(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
 pub struct Parser {}
 impl Parser {
     pub fn parse(src_code: String) -> Program {
+        let mut instructions = vec![];
+        let mut locations = vec![];
+        let mut loc = SourceLoc { line: 1, col: 1 };
+        for c in src_code.chars() {
+            if ['>', '<', '+', '-', '.', ',', '[', ']'].contains(&c) {
+                instructions.push(c);
+                locations.push(loc);
+            }
+            if c == '\n' {
+                loc.line += 1;
+                loc.col = 1;
+            } else {
+                loc.col += 1;
+            }
+        }
         Program {
-            instructions: src_code
-                .as_bytes()
-                .into_iter()
-                .filter(|x| ['>', '<', '+', '-', '.', ',', '[', ']'].contains(&(**x as char)))
-                .map(|x| (*x as char))
-                .collect::<Vec<char>>(),
+            instructions,
+            locations,
         }
     }
 
@@ -26,9 +60,11 @@ impl Parser {
     pub fn parse_to_bytecode(src_code: String) -> ByteCodeProgram {
         let program = Self::parse(src_code);
         let mut bytecode_instrs = vec![];
+        let mut spans = vec![];
         let mut index = 0;
         let prog_size = program.instructions.len();
         while index < prog_size {
+            let start = program.locations[index];
             bytecode_instrs.push(match program.instructions[index] {
                 '[' => ByteCode::JZ,
                 ']' => ByteCode::JNZ,
@@ -56,17 +92,25 @@ impl Parser {
                 }
                 _ => ByteCode::Nop,
             });
+            spans.push(Span {
+                start,
+                end: program.locations[index],
+            });
             index += 1;
         }
         ByteCodeProgram {
             instructions: bytecode_instrs,
+            spans,
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{bytecode_bf::ByteCode, parser::Parser};
+    use crate::{
+        bytecode_bf::ByteCode,
+        parser::{Parser, SourceLoc, Span},
+    };
 
     #[test]
     fn bytecode_parser() {
@@ -86,4 +130,30 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn source_locations() {
+        let code = "a+\n +b\n[-]";
+        let prog = Parser::parse(code.to_owned());
+        assert_eq!(
+            prog.locations,
+            vec![
+                SourceLoc { line: 1, col: 2 },
+                SourceLoc { line: 2, col: 2 },
+                SourceLoc { line: 3, col: 1 },
+                SourceLoc { line: 3, col: 2 },
+                SourceLoc { line: 3, col: 3 },
+            ]
+        );
+
+        let bytecode = Parser::parse_to_bytecode(code.to_owned());
+        assert_eq!(
+            bytecode.spans[0],
+            Span {
+                start: SourceLoc { line: 1, col: 2 },
+                end: SourceLoc { line: 2, col: 2 },
+            }
+        );
+        assert_eq!(bytecode.spans.len(), bytecode.instructions.len());
+    }
 }
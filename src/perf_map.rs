@@ -0,0 +1,70 @@
+use std::{fs::OpenOptions, io::Write, path::PathBuf};
+
+use crate::jit_utils::CodeRegion;
+
+/// The file `perf` reads to symbolize JIT code of the current process.
+pub fn perf_map_path() -> PathBuf {
+    PathBuf::from(format!("/tmp/perf-{}.map", std::process::id()))
+}
+
+/// Writes one `START SIZE name` line (hex addresses) per non-empty region,
+/// with region offsets relative to `base`.
+pub fn write_regions<W: Write>(
+    out: &mut W,
+    base: usize,
+    regions: &[CodeRegion],
+) -> std::io::Result<()> {
+    for region in regions.iter().filter(|x| x.end > x.start) {
+        writeln!(
+            out,
+            "{:x} {:x} {}",
+            base + region.start,
+            region.end - region.start,
+            region.name
+        )?;
+    }
+    Ok(())
+}
+
+/// Appends the regions of code loaded at `base` to the perf map of this process.
+pub fn append_to_perf_map(base: usize, regions: &[CodeRegion]) -> std::io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(perf_map_path())?;
+    write_regions(&mut file, base, regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jit_utils::CodeRegion;
+
+    use super::write_regions;
+
+    #[test]
+    fn perf_map_format() {
+        let regions = vec![
+            CodeRegion {
+                name: "bf_program".to_owned(),
+                start: 0,
+                end: 0x10,
+            },
+            CodeRegion {
+                name: "bf_loop@1:3-1:9".to_owned(),
+                start: 0x10,
+                end: 0x10,
+            },
+            CodeRegion {
+                name: "bf_loop@2:1-2:5".to_owned(),
+                start: 0x10,
+                end: 0x2a,
+            },
+        ];
+        let mut out = vec![];
+        write_regions(&mut out, 0x7f0000001000, &regions).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "7f0000001000 10 bf_program\n7f0000001010 1a bf_loop@2:1-2:5\n"
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::{collections::HashMap, io::Write};
+
+use crate::{
+    bf::Program,
+    bytecode_bf::{ByteCode, ByteCodeProgram},
+    observer::ExecutionObserver,
+    parser::SourceLoc,
+};
+
+#[derive(Clone, Copy)]
+enum Bracket {
+    Open(SourceLoc),
+    Close,
+}
+
+/// Samples executed instructions by loop nesting.
+///
+/// Every `[...]` loop is a stack frame named after the source location of its
+/// `[`, and every executed instruction is one sample attributed to the
+/// innermost loop running at the time. The result is written in the folded
+/// stack format consumed by `flamegraph.pl` and `inferno-flamegraph`.
+pub struct LoopProfiler {
+    brackets: Vec<Option<Bracket>>,
+    // Frame tree: node 0 is the root (outside of every loop).
+    parents: Vec<usize>,
+    frames: Vec<Option<SourceLoc>>,
+    children: HashMap<(usize, usize), usize>,
+    samples: Vec<u64>,
+    current: usize,
+}
+
+impl LoopProfiler {
+    fn new(brackets: Vec<Option<Bracket>>) -> Self {
+        LoopProfiler {
+            brackets,
+            parents: vec![0],
+            frames: vec![None],
+            children: HashMap::new(),
+            samples: vec![0],
+            current: 0,
+        }
+    }
+
+    pub fn for_program(prog: &Program) -> Self {
+        Self::new(
+            prog.instructions
+                .iter()
+                .zip(&prog.locations)
+                .map(|(instr, loc)| match instr {
+                    '[' => Some(Bracket::Open(*loc)),
+                    ']' => Some(Bracket::Close),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    pub fn for_bytecode(prog: &ByteCodeProgram) -> Self {
+        Self::new(
+            prog.instructions
+                .iter()
+                .zip(&prog.spans)
+                .map(|(instr, span)| match instr {
+                    ByteCode::JZ => Some(Bracket::Open(span.start)),
+                    ByteCode::JNZ => Some(Bracket::Close),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    fn enter(&mut self, pc: usize, loc: SourceLoc) {
+        let next_node = self.samples.len();
+        let node = *self.children.entry((self.current, pc)).or_insert(next_node);
+        if node == next_node {
+            self.parents.push(self.current);
+            self.frames.push(Some(loc));
+            self.samples.push(0);
+        }
+        self.current = node;
+    }
+
+    fn stack(&self, mut node: usize, root: &str) -> String {
+        let mut frames = vec![];
+        while node != 0 {
+            let loc = self.frames[node].unwrap();
+            frames.push(format!("loop@{}:{}", loc.line, loc.col));
+            node = self.parents[node];
+        }
+        frames.push(root.to_owned());
+        frames.reverse();
+        frames.join(";")
+    }
+
+    /// One `stack count` line per loop nesting that executed anything, with
+    /// `root` as the outermost frame.
+    pub fn folded_stacks(&self, root: &str) -> Vec<(String, u64)> {
+        let mut stacks: Vec<_> = (0..self.samples.len())
+            .filter(|node| self.samples[*node] > 0)
+            .map(|node| (self.stack(node, root), self.samples[node]))
+            .collect();
+        stacks.sort();
+        stacks
+    }
+
+    pub fn write_folded<W: Write>(&self, root: &str, out: &mut W) -> std::io::Result<()> {
+        for (stack, count) in self.folded_stacks(root) {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl ExecutionObserver for LoopProfiler {
+    fn instruction(&mut self, _pc: usize) {
+        self.samples[self.current] += 1;
+    }
+
+    fn branch(&mut self, pc: usize, taken: bool) {
+        match self.brackets[pc] {
+            // `[` falls through into the loop body
+            Some(Bracket::Open(loc)) if !taken => self.enter(pc, loc),
+            // `]` falls through out of the loop
+            Some(Bracket::Close) if !taken => self.current = self.parents[self.current],
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::Parser;
+
+    use super::LoopProfiler;
+
+    const CODE: &str = "++[>+++[>+<-]<-]";
+
+    #[test]
+    fn interpreter_profile() {
+        let prog = Parser::parse(CODE.to_owned());
+        let mut profiler = LoopProfiler::for_program(&prog);
+        prog.eval_with_observer(&mut profiler);
+
+        assert_eq!(
+            profiler.folded_stacks("main"),
+            vec![
+                ("main".to_owned(), 3),
+                ("main;loop@1:3".to_owned(), 16),
+                ("main;loop@1:3;loop@1:8".to_owned(), 30),
+            ]
+        );
+    }
+
+    #[test]
+    fn bytecode_profile() {
+        let prog = Parser::parse_to_bytecode(CODE.to_owned());
+        let mut profiler = LoopProfiler::for_bytecode(&prog);
+        prog.eval_with_observer(&mut profiler);
+
+        let mut out = vec![];
+        profiler.write_folded("main", &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "main 2\nmain;loop@1:3 12\nmain;loop@1:3;loop@1:8 30\n"
+        );
+    }
+}
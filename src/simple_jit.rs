@@ -1,33 +1,174 @@
 use std::mem::transmute_copy;
+use std::os::unix::io::RawFd;
 
 use crate::{
-    jit_utils::{compute_relative_32bit_offset, CodeEmitter, JitProgram},
-    parser, MEMORY_SIZE,
+    bf::Program,
+    disasm,
+    elf::{self, ElfBuilder, Section, Segment, Symbol},
+    gdb_jit::{self, GdbRegistration},
+    jit_utils::{compute_relative_32bit_offset, CodeEmitter, JitOptions, JitProgram, LoopRegions},
+    parser::{self, SourceLoc},
+    perf_map, MEMORY_SIZE,
 };
 
+/// The file descriptors of stdin and stdout.
+const STDIO: (RawFd, RawFd) = (0, 1);
+
+// Where `build_executable` loads the code and the tape.
+const EXE_TEXT_ADDR: u64 = 0x401000;
+const EXE_TAPE_ADDR: u64 = 0x600000;
+
 pub struct SimpleJit {}
 
 impl SimpleJit {
     pub fn parse_and_run(src: String) {
+        Self::parse_and_run_with_options(src, &JitOptions::default());
+    }
+
+    pub fn parse_and_run_with_options(src: String, options: &JitOptions) {
         let mut memory = vec![0 as u8; MEMORY_SIZE];
+        let prog = parser::Parser::parse(src);
+        let mut emitter = CodeEmitter::new();
+        let (regions, line_rows) =
+            Self::emit_function(&prog, memory.as_mut_ptr() as u64, STDIO, &mut emitter);
+        let regions = regions.finish(emitter.size());
 
-        // Registers used in the program:
-        //
-        // r13: the data pointer -- contains the address of memory.data()
-        //
-        // rax, rdi, rsi, rdx: used for making system calls, per the ABI.
+        if options.dump_asm {
+            let mut annotations = vec![(0, String::from("prologue"))];
+            annotations.extend(line_rows.iter().zip(&prog.instructions).map(
+                |((offset, loc), instr)| {
+                    (*offset, format!("'{}' at {}:{}", instr, loc.line, loc.col))
+                },
+            ));
+            annotations.push((emitter.size() - 3, String::from("epilogue")));
+            print!("{}", disasm::disassemble(emitter.code(), &annotations));
+            return;
+        }
+        unsafe {
+            let program = JitProgram::new(emitter.code().clone());
+            if options.perf_map {
+                perf_map::append_to_perf_map(program.program_memory() as usize, &regions)
+                    .expect("Failed to write perf map");
+            }
+            let _gdb_registration = options.gdb.then(|| {
+                GdbRegistration::register(gdb_jit::symbol_file(
+                    options.source_name(),
+                    program.program_memory() as usize,
+                    program.program_size(),
+                    &regions,
+                    &line_rows,
+                ))
+            });
+            let jit_fn: unsafe extern "C" fn() -> () = transmute_copy(&program.program_memory());
+            jit_fn();
+        }
+        println!("");
+    }
 
+    /// Machine code for `prog`, working on the tape at `tape` and reading
+    /// and writing straight to the file descriptors `(input, output)`, as
+    /// `extern "C" fn()`.
+    pub fn compile(prog: &Program, tape: *mut u8, fds: (RawFd, RawFd)) -> Vec<u8> {
         let mut emitter = CodeEmitter::new();
+        Self::emit_function(prog, tape as u64, fds, &mut emitter);
+        emitter.code().clone()
+    }
+
+    /// `emit_program` as an `extern "C" fn()`, which saves the callee-saved
+    /// r13 it uses for the data pointer.
+    fn emit_function(
+        prog: &Program,
+        tape: u64,
+        fds: (RawFd, RawFd),
+        emitter: &mut CodeEmitter,
+    ) -> (LoopRegions, Vec<(usize, SourceLoc)>) {
+        // push %r13
+        emitter.emit_bytes(&[0x41, 0x55]);
+        let result = Self::emit_program(prog, tape, fds, emitter);
+        // pop %r13
+        // ret
+        emitter.emit_bytes(&[0x41, 0x5D]);
+        emitter.emit_byte(0xC3);
+        result
+    }
 
+    /// A static x86-64 Linux executable for `src`, with the tape in `.bss`.
+    /// It needs neither LLVM nor a linker: the code only makes raw syscalls,
+    /// and ends with `exit` instead of returning.
+    pub fn parse_and_build_executable(src: String) -> Vec<u8> {
         let prog = parser::Parser::parse(src);
+        let mut emitter = CodeEmitter::new();
+        let (regions, _) = Self::emit_program(&prog, EXE_TAPE_ADDR, STDIO, &mut emitter);
+        // mov $60, %eax
+        // xor %edi, %edi
+        // syscall
+        emitter.emit_bytes(&[0xB8, 0x3C, 0x00, 0x00, 0x00]);
+        emitter.emit_bytes(&[0x31, 0xFF]);
+        emitter.emit_bytes(&[0x0F, 0x05]);
+        let regions = regions.finish(emitter.size());
+
+        let mut elf = ElfBuilder::new(elf::ET_EXEC);
+        let mut text = Section::progbits(
+            ".text",
+            elf::SHF_ALLOC | elf::SHF_EXECINSTR,
+            EXE_TEXT_ADDR,
+            emitter.code().clone(),
+        );
+        text.align = elf::PAGE_SIZE;
+        let text = elf.add_section(text);
+        let bss = elf.add_section(Section::nobits(
+            ".bss",
+            elf::SHF_ALLOC | elf::SHF_WRITE,
+            EXE_TAPE_ADDR,
+            MEMORY_SIZE as u64,
+        ));
+        elf.add_segment(Segment {
+            section: text,
+            flags: elf::PF_R | elf::PF_X,
+        });
+        elf.add_segment(Segment {
+            section: bss,
+            flags: elf::PF_R | elf::PF_W,
+        });
+        elf.set_entry(EXE_TEXT_ADDR);
+        for region in regions {
+            elf.add_symbol(Symbol {
+                name: region.name,
+                value: EXE_TEXT_ADDR + region.start as u64,
+                size: (region.end - region.start) as u64,
+                section: text,
+                kind: elf::STT_FUNC,
+            });
+        }
+        elf.build()
+    }
+
+    /// Emits `prog` working on the tape at address `tape` and the file
+    /// descriptors `(input, output)`, without the final `ret`. Returns its
+    /// loops and the (code offset, source location) of every instruction for
+    /// gdb.
+    fn emit_program(
+        prog: &Program,
+        tape: u64,
+        (input, output): (RawFd, RawFd),
+        emitter: &mut CodeEmitter,
+    ) -> (LoopRegions, Vec<(usize, SourceLoc)>) {
+        // Registers used in the program:
+        //
+        // r13: the data pointer -- contains the address of the tape
+        //
+        // rax, rdi, rsi, rdx: used for making system calls, per the ABI.
 
         let mut open_bracket_stack: Vec<usize> = vec![];
+        let mut regions = LoopRegions::new(emitter.size());
+        let mut line_rows = vec![];
 
-        // movabs <address of memory.data>, %r13
+        // movabs <address of the tape>, %r13
         emitter.emit_bytes(&[0x49, 0xBD]);
-        emitter.emit_uint64(memory.as_mut_ptr() as u64);
+        emitter.emit_uint64(tape);
 
         for (pc, instr) in prog.instructions.iter().enumerate() {
+            line_rows.push((emitter.size(), prog.locations[pc]));
             match instr {
                 // inc %r13
                 '>' => emitter.emit_bytes(&[0x49, 0xFF, 0xC5]),
@@ -39,31 +180,33 @@ impl SimpleJit {
                 // subb $1, 0(%r13)
                 '-' => emitter.emit_bytes(&[0x41, 0x80, 0x6D, 0x00, 0x01]),
                 '.' => {
-                    // To emit one byte to stdout, call the write syscall with fd=1 (for
-                    // stdout), buf=address of byte, count=1.
+                    // To emit one byte, call the write syscall with fd=output,
+                    // buf=address of byte, count=1.
                     //
                     // mov $1, %rax
-                    // mov $1, %rdi
+                    // mov $output, %rdi
                     // mov %r13, %rsi
                     // mov $1, %rdx
                     // syscall
                     emitter.emit_bytes(&[0x48, 0xC7, 0xC0, 0x01, 0x00, 0x00, 0x00]);
-                    emitter.emit_bytes(&[0x48, 0xC7, 0xC7, 0x01, 0x00, 0x00, 0x00]);
+                    emitter.emit_bytes(&[0x48, 0xC7, 0xC7]);
+                    emitter.emit_uint32(output as u32);
                     emitter.emit_bytes(&[0x4C, 0x89, 0xEE]);
                     emitter.emit_bytes(&[0x48, 0xC7, 0xC2, 0x01, 0x00, 0x00, 0x00]);
                     emitter.emit_bytes(&[0x0F, 0x05]);
                 }
                 ',' => {
-                    // To read one byte from stdin, call the read syscall with fd=0 (for
-                    // stdin),
+                    // To read one byte, call the read syscall with fd=input,
                     // buf=address of byte, count=1.
                     emitter.emit_bytes(&[0x48, 0xC7, 0xC0, 0x00, 0x00, 0x00, 0x00]);
-                    emitter.emit_bytes(&[0x48, 0xC7, 0xC7, 0x00, 0x00, 0x00, 0x00]);
+                    emitter.emit_bytes(&[0x48, 0xC7, 0xC7]);
+                    emitter.emit_uint32(input as u32);
                     emitter.emit_bytes(&[0x4C, 0x89, 0xEE]);
                     emitter.emit_bytes(&[0x48, 0xC7, 0xC2, 0x01, 0x00, 0x00, 0x00]);
                     emitter.emit_bytes(&[0x0F, 0x05]);
                 }
                 '[' => {
+                    regions.enter_loop(emitter.size(), prog.locations[pc]);
                     // cmpb $0, 0(%r13)
                     emitter.emit_bytes(&[0x41, 0x80, 0x7d, 0x00, 0x00]);
 
@@ -97,26 +240,35 @@ impl SimpleJit {
                     let jump_forward_to = emitter.size();
                     let offset = compute_relative_32bit_offset(jump_forward_from, jump_forward_to);
                     emitter.replace_uint32_at_offset(last_open_bracket + 2, offset);
+                    regions.leave_loop(emitter.size(), prog.locations[pc]);
                 }
 
                 _ => panic!("Invalid character"),
             }
         }
-        emitter.emit_byte(0xC3);
-        unsafe {
-            let program = JitProgram::new(emitter.code().clone());
-            let jit_fn: unsafe extern "C" fn() -> () = transmute_copy(&program.program_memory());
-            jit_fn();
-        }
-        println!("");
+        (regions, line_rows)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Command;
 
     use super::SimpleJit;
 
+    #[test]
+    fn executable() {
+        let code = include_str!("../programs/hello_world.bf");
+        let exe = std::env::temp_dir().join(format!("bf-exe-{}", std::process::id()));
+        std::fs::write(&exe, SimpleJit::parse_and_build_executable(code.to_owned())).unwrap();
+        std::fs::set_permissions(&exe, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let output = Command::new(&exe).output().unwrap();
+        std::fs::remove_file(&exe).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"Hello World!\n");
+    }
+
     #[test]
     fn hello_world() {
         let code = include_str!("../programs/hello_world.bf");
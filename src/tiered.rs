@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use crate::{
+    bytecode_bf::ByteCodeProgram,
+    compact::{CompactProgram, Instructions, Op},
+    io::Io,
+    jit_utils::{JitOptions, STATUS_OUT_OF_BOUNDS},
+    optbytecode_jit::{BytecodeJit, CompiledCode},
+    parser::Parser,
+    MEMORY_SIZE,
+};
+
+/// Iterations of a loop after which `TieredJit` compiles it by default.
+pub const HOT_LOOP_THRESHOLD: u32 = 1000;
+
+/// Starts programs in a bytecode interpreter, which counts the iterations of
+/// every loop. Once a loop runs `threshold` of them, `BytecodeJit` compiles
+/// it, and the interpreter hands the data pointer over to the compiled loop
+/// right away, in the middle of the loop, and again every time the loop
+/// starts later. The interpreter carries on after the loop exits.
+///
+/// Short programs never wait for a compiler, and long running ones spend
+/// their time in machine code.
+pub struct TieredJit {
+    pub threshold: u32,
+}
+
+impl Default for TieredJit {
+    fn default() -> Self {
+        TieredJit {
+            threshold: HOT_LOOP_THRESHOLD,
+        }
+    }
+}
+
+impl TieredJit {
+    pub fn parse_and_run(src: String) {
+        Self::parse_and_run_with_options(src, &JitOptions::default());
+    }
+
+    /// Runs `src` on stdin and stdout.
+    pub fn parse_and_run_with_options(src: String, options: &JitOptions) {
+        let mut prog = Parser::parse_to_bytecode(src);
+        prog.opt_pass_1();
+        prog.opt_pass_2();
+        let mut tape = vec![0; MEMORY_SIZE];
+        let (stdin, stdout) = (std::io::stdin(), std::io::stdout());
+        let mut io = Io::new(stdin.lock(), stdout.lock(), options.eof_policy);
+        Self::default().run(&prog, &mut tape, &mut io);
+        io.finish().expect("Failed to write output");
+    }
+
+    /// Runs `prog` on `tape`, and returns the `JZ`s of the loops that got
+    /// compiled, in the order they were. Panics when the data pointer
+    /// leaves the tape, in the interpreter and in compiled loops alike.
+    pub fn run<R: Read, W: Write>(
+        &self,
+        prog: &ByteCodeProgram,
+        tape: &mut [u8],
+        io: &mut Io<R, W>,
+    ) -> Vec<usize> {
+        match CompactProgram::from_bytecode(prog) {
+            Ok(code) => self.interpret(prog, &code, tape, io),
+            // too large for the compact encoding
+            Err(_) => self.interpret(prog, Op::from_bytecode(prog).as_slice(), tape, io),
+        }
+    }
+
+    /// `run` with `code` for the instructions of `prog`.
+    fn interpret<I: Instructions + ?Sized, R: Read, W: Write>(
+        &self,
+        prog: &ByteCodeProgram,
+        code: &I,
+        tape: &mut [u8],
+        io: &mut Io<R, W>,
+    ) -> Vec<usize> {
+        // iterations per JZ
+        let mut iterations = vec![0; prog.instructions.len()];
+        let mut compiled: HashMap<usize, CompiledCode> = HashMap::new();
+        let mut compile_order = vec![];
+        let mut dataptr: usize = 0;
+        let mut pc = 0;
+        while let Some(op) = code.get(pc) {
+            match op {
+                Op::Nop => {}
+                Op::Right(x) => dataptr += x,
+                Op::Left(x) => {
+                    dataptr = dataptr
+                        .checked_sub(x)
+                        .unwrap_or_else(|| panic!("Data pointer out of bounds at pc={}", pc));
+                }
+                Op::Add(x) => tape[dataptr] = tape[dataptr].wrapping_add(x as u8),
+                Op::Sub(x) => tape[dataptr] = tape[dataptr].wrapping_sub(x as u8),
+                Op::Write => io.write(tape[dataptr]),
+                Op::Read => tape[dataptr] = io.read(tape[dataptr]),
+                Op::JumpIfZero(jnz) => {
+                    if tape[dataptr] == 0 {
+                        pc = jnz;
+                    } else if let Some(compiled_loop) = compiled.get(&pc) {
+                        dataptr = run_compiled(compiled_loop, tape, dataptr, io);
+                        pc = jnz;
+                    }
+                }
+                Op::JumpIfNotZero(jz) => {
+                    if tape[dataptr] != 0 {
+                        iterations[jz] += 1;
+                        if iterations[jz] < self.threshold {
+                            pc = jz;
+                        } else {
+                            // The cell isn't 0, so the compiled loop starts
+                            // with the next iteration, and ends at this JNZ.
+                            let compiled_loop = compiled.entry(jz).or_insert_with(|| {
+                                compile_order.push(jz);
+                                BytecodeJit::compile_loop::<R, W>(prog, jz, pc)
+                            });
+                            dataptr = run_compiled(compiled_loop, tape, dataptr, io);
+                        }
+                    }
+                }
+                Op::SetZero => tape[dataptr] = 0,
+                Op::ScanRight(x) => {
+                    while tape[dataptr] != 0 {
+                        dataptr += x;
+                    }
+                }
+                Op::ScanLeft(x) => {
+                    while tape[dataptr] != 0 {
+                        dataptr = dataptr
+                            .checked_sub(x)
+                            .unwrap_or_else(|| panic!("Data pointer out of bounds at pc={}", pc));
+                    }
+                }
+                Op::MultiplyAdd(offset, factor) => {
+                    let value = tape[dataptr];
+                    if value != 0 {
+                        let target = dataptr
+                            .checked_add_signed(offset)
+                            .unwrap_or_else(|| panic!("Data pointer out of bounds at pc={}", pc));
+                        tape[target] = tape[target].wrapping_add(value.wrapping_mul(factor));
+                    }
+                }
+            }
+            pc += 1;
+        }
+        compile_order
+    }
+}
+
+/// Runs `compiled_loop`, compiled for `Io<R, W>`, from the cell at
+/// `dataptr`, and returns the data pointer it ends up at.
+fn run_compiled<R: Read, W: Write>(
+    compiled_loop: &CompiledCode,
+    tape: &mut [u8],
+    dataptr: usize,
+    io: &mut Io<R, W>,
+) -> usize {
+    let io = io as *mut Io<R, W> as *mut u8;
+    let result = unsafe { compiled_loop.run(tape, dataptr, io) };
+    if result.status == STATUS_OUT_OF_BOUNDS {
+        panic!("Data pointer out of bounds: {}", result.dataptr);
+    }
+    result.dataptr as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TieredJit;
+    use crate::bytecode_bf::{ByteCode, ByteCodeProgram};
+    use crate::io::{EofPolicy, Io};
+    use crate::parser::Parser;
+
+    /// Runs `prog` compiling loops after `threshold` iterations, and returns
+    /// its output and the loops it compiled.
+    fn run(
+        prog: &ByteCodeProgram,
+        threshold: u32,
+        input: &[u8],
+        tape: &mut [u8],
+    ) -> (Vec<u8>, Vec<usize>) {
+        let mut output = vec![];
+        let mut io = Io::new(input, &mut output, EofPolicy::Zero);
+        let compiled = TieredJit { threshold }.run(prog, tape, &mut io);
+        io.finish().unwrap();
+        (output, compiled)
+    }
+
+    #[test]
+    fn hello_world() {
+        let code = include_str!("../programs/hello_world.bf");
+        let mut prog = Parser::parse_to_bytecode(code.to_owned());
+        prog.opt_pass_1();
+        prog.opt_pass_2();
+        for threshold in [1, 10, u32::MAX] {
+            let (output, _) = run(&prog, threshold, b"", &mut [0; 100]);
+            assert_eq!(output, b"Hello World!\n");
+        }
+    }
+
+    #[test]
+    fn large_operands() {
+        // too large for the compact encoding, but fine uncompacted
+        let prog = ByteCodeProgram {
+            instructions: vec![
+                ByteCode::DataPointerIncr(1 << 24),
+                ByteCode::DataPointerDecr(1 << 24),
+                ByteCode::DataIncr(1 << 24 | 65),
+                ByteCode::Write,
+            ],
+            spans: vec![],
+        };
+        let (output, _) = run(&prog, 1, b"", &mut [0; 1]);
+        assert_eq!(output, b"A");
+    }
+
+    #[test]
+    #[should_panic(expected = "Data pointer out of bounds: 4")]
+    fn out_of_bounds() {
+        // compiled after the first iteration, which stays on the tape
+        let prog = Parser::parse_to_bytecode("+[>+]".to_owned());
+        run(&prog, 1, b"", &mut [0; 4]);
+    }
+
+    #[test]
+    fn hot_loops() {
+        // the inner loop runs 3 times per outer iteration, and gets compiled
+        // in the first one
+        let prog = Parser::parse_to_bytecode("+++++[>+++[>+<-]<-]".to_owned());
+        let loops: Vec<usize> = (0..prog.instructions.len())
+            .filter(|pc| prog.instructions[*pc] == ByteCode::JZ)
+            .collect();
+        let mut tape = [0; 3];
+        let (_, compiled) = run(&prog, 2, b"", &mut tape);
+        assert_eq!(compiled, [loops[1], loops[0]]);
+        assert_eq!(tape, [0, 0, 15]);
+
+        let mut tape = [0; 3];
+        let (_, compiled) = run(&prog, 100, b"", &mut tape);
+        assert!(compiled.is_empty());
+        assert_eq!(tape, [0, 0, 15]);
+    }
+}
@@ -0,0 +1,332 @@
+use crate::{
+    bytecode_bf::{ByteCode, ByteCodeProgram, Change},
+    io::EofPolicy,
+    jit_utils::JitOptions,
+    parser::Parser,
+    MEMORY_SIZE,
+};
+
+const WASM_MAGIC: &[u8] = b"\0asm";
+const WASM_VERSION: u32 = 1;
+const PAGE_SIZE: usize = 1 << 16;
+
+const SECTION_TYPE: u8 = 1;
+const SECTION_IMPORT: u8 = 2;
+const SECTION_FUNCTION: u8 = 3;
+const SECTION_MEMORY: u8 = 5;
+const SECTION_EXPORT: u8 = 7;
+const SECTION_CODE: u8 = 10;
+
+const TYPE_FUNC: u8 = 0x60;
+const TYPE_I32: u8 = 0x7f;
+const BLOCK_EMPTY: u8 = 0x40;
+const KIND_FUNC: u8 = 0x00;
+const KIND_MEMORY: u8 = 0x02;
+
+const OP_BLOCK: u8 = 0x02;
+const OP_LOOP: u8 = 0x03;
+const OP_IF: u8 = 0x04;
+const OP_END: u8 = 0x0b;
+const OP_BR: u8 = 0x0c;
+const OP_BR_IF: u8 = 0x0d;
+const OP_CALL: u8 = 0x10;
+const OP_SELECT: u8 = 0x1b;
+const OP_LOCAL_GET: u8 = 0x20;
+const OP_LOCAL_SET: u8 = 0x21;
+const OP_LOCAL_TEE: u8 = 0x22;
+const OP_I32_LOAD8_U: u8 = 0x2d;
+const OP_I32_STORE8: u8 = 0x3a;
+const OP_I32_CONST: u8 = 0x41;
+const OP_I32_EQZ: u8 = 0x45;
+const OP_I32_GE_S: u8 = 0x4e;
+const OP_I32_ADD: u8 = 0x6a;
+const OP_I32_SUB: u8 = 0x6b;
+const OP_I32_MUL: u8 = 0x6c;
+
+// Indices in the module built by `emit`: the imports come before `run`.
+const FUNC_READ: u32 = 0;
+const FUNC_WRITE: u32 = 1;
+const FUNC_RUN: u32 = 2;
+const LOCAL_P: u32 = 0;
+const LOCAL_C: u32 = 1;
+
+/// Turns bytecode into a WebAssembly module, to run in browsers and wasm
+/// runtimes.
+pub struct WasmEmitter {}
+
+impl WasmEmitter {
+    /// Parses and optimizes `src` for `emit`.
+    pub fn parse_and_emit(src: String, options: &JitOptions) -> Vec<u8> {
+        let mut prog = Parser::parse_to_bytecode(src);
+        prog.opt_pass_1();
+        prog.opt_pass_2();
+        Self::emit(&prog, options)
+    }
+
+    /// Binary module for `prog`. It imports
+    ///
+    /// - `env.read: () -> i32`, the next input byte or -1 at the end of input,
+    ///   where `,` follows `options.eof_policy`, and
+    /// - `env.write: (i32) -> ()`, called with each output byte,
+    ///
+    /// and exports the `memory` holding the tape, at address 0, and `run`,
+    /// which runs the program once. Like the C backend it doesn't check that
+    /// the pointer stays on the tape, the module only traps when it leaves
+    /// the memory.
+    pub fn emit(prog: &ByteCodeProgram, options: &JitOptions) -> Vec<u8> {
+        let mut module = WASM_MAGIC.to_vec();
+        module.extend_from_slice(&WASM_VERSION.to_le_bytes());
+
+        // read, write and run
+        let mut types = vec![];
+        write_u32(&mut types, 3);
+        types.extend_from_slice(&[TYPE_FUNC, 0, 1, TYPE_I32]);
+        types.extend_from_slice(&[TYPE_FUNC, 1, TYPE_I32, 0]);
+        types.extend_from_slice(&[TYPE_FUNC, 0, 0]);
+        section(&mut module, SECTION_TYPE, &types);
+
+        let mut imports = vec![];
+        write_u32(&mut imports, 2);
+        for (index, name) in [(FUNC_READ, "read"), (FUNC_WRITE, "write")] {
+            write_name(&mut imports, "env");
+            write_name(&mut imports, name);
+            imports.push(KIND_FUNC);
+            // each has the type of the same index
+            write_u32(&mut imports, index);
+        }
+        section(&mut module, SECTION_IMPORT, &imports);
+
+        let mut functions = vec![];
+        write_u32(&mut functions, 1);
+        write_u32(&mut functions, 2);
+        section(&mut module, SECTION_FUNCTION, &functions);
+
+        // no maximum
+        let mut memories = vec![];
+        write_u32(&mut memories, 1);
+        memories.push(0);
+        write_u32(&mut memories, MEMORY_SIZE.div_ceil(PAGE_SIZE) as u32);
+        section(&mut module, SECTION_MEMORY, &memories);
+
+        let mut exports = vec![];
+        write_u32(&mut exports, 2);
+        write_name(&mut exports, "memory");
+        exports.push(KIND_MEMORY);
+        write_u32(&mut exports, 0);
+        write_name(&mut exports, "run");
+        exports.push(KIND_FUNC);
+        write_u32(&mut exports, FUNC_RUN);
+        section(&mut module, SECTION_EXPORT, &exports);
+
+        let body = Self::function_body(prog, options);
+        let mut code = vec![];
+        write_u32(&mut code, 1);
+        write_u32(&mut code, body.len() as u32);
+        code.extend(body);
+        section(&mut module, SECTION_CODE, &code);
+        module
+    }
+
+    /// `run`, with the data pointer in local `p` and the last byte read in
+    /// local `c`.
+    fn function_body(prog: &ByteCodeProgram, options: &JitOptions) -> Vec<u8> {
+        let mut f = FunctionBody { code: vec![] };
+        // two i32 locals
+        f.code.extend_from_slice(&[1, 2, TYPE_I32]);
+        for instr in &prog.instructions {
+            match *instr {
+                ByteCode::Nop => {}
+                ByteCode::DataPointerIncr(n) => f.move_pointer(OP_I32_ADD, n),
+                ByteCode::DataPointerDecr(n) => f.move_pointer(OP_I32_SUB, n),
+                ByteCode::DataIncr(n) => f.add_to_cell(OP_I32_ADD, n),
+                ByteCode::DataDecr(n) => f.add_to_cell(OP_I32_SUB, n),
+                ByteCode::Write => {
+                    f.load_cell();
+                    f.op_u32(OP_CALL, FUNC_WRITE);
+                }
+                ByteCode::Read => {
+                    // p, then c if it's a byte and the EOF value if not
+                    f.op_u32(OP_LOCAL_GET, LOCAL_P);
+                    f.op_u32(OP_CALL, FUNC_READ);
+                    f.op_u32(OP_LOCAL_TEE, LOCAL_C);
+                    match options.eof_policy {
+                        EofPolicy::Zero => f.i32_const(0),
+                        EofPolicy::AllOnes => f.i32_const(255),
+                        EofPolicy::Unchanged => f.load_cell(),
+                    }
+                    f.op_u32(OP_LOCAL_GET, LOCAL_C);
+                    f.i32_const(0);
+                    f.code.extend_from_slice(&[OP_I32_GE_S, OP_SELECT]);
+                    f.store8();
+                }
+                ByteCode::JZ => {
+                    // block { if !*p break; loop { ...
+                    f.code.extend_from_slice(&[OP_BLOCK, BLOCK_EMPTY]);
+                    f.load_cell();
+                    f.code.push(OP_I32_EQZ);
+                    f.op_u32(OP_BR_IF, 0);
+                    f.code.extend_from_slice(&[OP_LOOP, BLOCK_EMPTY]);
+                }
+                ByteCode::JNZ => {
+                    // ... if *p continue; } }
+                    f.load_cell();
+                    f.op_u32(OP_BR_IF, 0);
+                    f.code.extend_from_slice(&[OP_END, OP_END]);
+                }
+                ByteCode::SETZERO => {
+                    f.op_u32(OP_LOCAL_GET, LOCAL_P);
+                    f.i32_const(0);
+                    f.store8();
+                }
+                ByteCode::MoveInStepUntilZero(change) => {
+                    // block { loop { if !*p break; p += n; continue; } }
+                    f.code
+                        .extend_from_slice(&[OP_BLOCK, BLOCK_EMPTY, OP_LOOP, BLOCK_EMPTY]);
+                    f.load_cell();
+                    f.code.push(OP_I32_EQZ);
+                    f.op_u32(OP_BR_IF, 1);
+                    match change {
+                        Change::Incr(n) => f.move_pointer(OP_I32_ADD, n),
+                        Change::Decr(n) => f.move_pointer(OP_I32_SUB, n),
+                    }
+                    f.op_u32(OP_BR, 0);
+                    f.code.extend_from_slice(&[OP_END, OP_END]);
+                }
+                ByteCode::MultiplyAdd(offset, factor) => {
+                    f.load_cell();
+                    f.code.extend_from_slice(&[OP_IF, BLOCK_EMPTY]);
+                    f.offset_pointer(offset);
+                    f.offset_pointer(offset);
+                    f.code.push(OP_I32_LOAD8_U);
+                    f.memarg();
+                    f.load_cell();
+                    f.i32_const(factor as i32);
+                    f.code.extend_from_slice(&[OP_I32_MUL, OP_I32_ADD]);
+                    f.store8();
+                    f.code.push(OP_END);
+                }
+            }
+        }
+        f.code.push(OP_END);
+        f.code
+    }
+}
+
+struct FunctionBody {
+    code: Vec<u8>,
+}
+
+impl FunctionBody {
+    fn op_u32(&mut self, op: u8, immediate: u32) {
+        self.code.push(op);
+        write_u32(&mut self.code, immediate);
+    }
+
+    fn i32_const(&mut self, value: i32) {
+        self.code.push(OP_I32_CONST);
+        write_i32(&mut self.code, value);
+    }
+
+    /// Alignment 1 and no offset, for every access to the tape.
+    fn memarg(&mut self) {
+        self.code.extend_from_slice(&[0, 0]);
+    }
+
+    /// Pushes `*p`.
+    fn load_cell(&mut self) {
+        self.op_u32(OP_LOCAL_GET, LOCAL_P);
+        self.code.push(OP_I32_LOAD8_U);
+        self.memarg();
+    }
+
+    /// Pops a value and an address and stores the value's low byte there.
+    fn store8(&mut self) {
+        self.code.push(OP_I32_STORE8);
+        self.memarg();
+    }
+
+    /// Pushes `p + offset`.
+    fn offset_pointer(&mut self, offset: isize) {
+        self.op_u32(OP_LOCAL_GET, LOCAL_P);
+        self.i32_const(offset as i32);
+        self.code.push(OP_I32_ADD);
+    }
+
+    /// `p = p <op> n`
+    fn move_pointer(&mut self, op: u8, n: usize) {
+        self.op_u32(OP_LOCAL_GET, LOCAL_P);
+        self.i32_const(n as i32);
+        self.code.push(op);
+        self.op_u32(OP_LOCAL_SET, LOCAL_P);
+    }
+
+    /// `*p = *p <op> n`
+    fn add_to_cell(&mut self, op: u8, n: usize) {
+        self.op_u32(OP_LOCAL_GET, LOCAL_P);
+        self.load_cell();
+        self.i32_const(n as u8 as i32);
+        self.code.push(op);
+        self.store8();
+    }
+}
+
+fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    module.push(id);
+    write_u32(module, contents.len() as u32);
+    module.extend_from_slice(contents);
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    write_u32(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+}
+
+/// Unsigned LEB128.
+fn write_u32(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Signed LEB128.
+fn write_i32(out: &mut Vec<u8>, mut value: i32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        // done once the rest is all sign bits, and the sign bit of `byte`
+        // agrees with them
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_i32;
+
+    #[test]
+    fn leb128() {
+        for (value, expected) in [
+            (0, &[0x00][..]),
+            (63, &[0x3f]),
+            (64, &[0xc0, 0x00]),
+            (-1, &[0x7f]),
+            (-64, &[0x40]),
+            (-65, &[0xbf, 0x7f]),
+            (30000, &[0xb0, 0xea, 0x01]),
+        ] {
+            let mut out = vec![];
+            write_i32(&mut out, value);
+            assert_eq!(out, expected, "{}", value);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Behaviour every backend has to agree on, checked through each of them.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bf_interpreter::asm_backend::{AsmEmitter, AsmOptions, AsmSyntax};
+use bf_interpreter::bytecode_bf::ByteCodeProgram;
+use bf_interpreter::c_backend::{COptions, CTranspiler};
+#[cfg(feature = "cranelift")]
+use bf_interpreter::cranelift_jit::CraneliftJit;
+use bf_interpreter::io::{EofPolicy, Io};
+use bf_interpreter::jit_utils::JitOptions;
+#[cfg(feature = "llvm")]
+use bf_interpreter::llvm_jit::LlvmJit;
+use bf_interpreter::optbytecode_jit::BytecodeJit;
+use bf_interpreter::parser::Parser;
+use bf_interpreter::threaded::ThreadedCode;
+use bf_interpreter::tiered::TieredJit;
+use bf_interpreter::wasm_backend::WasmEmitter;
+use wasmi::{Caller, Engine, Linker, Module, Store};
+
+/// Large enough for every case below.
+const MEMORY_SIZE: usize = 1000;
+
+/// Runs a program on the given input, with `,` following the policy, and
+/// returns its output.
+type Backend = fn(&str, &[u8], EofPolicy) -> Vec<u8>;
+
+fn backends() -> Vec<(&'static str, Backend)> {
+    let mut backends: Vec<(&'static str, Backend)> = vec![
+        ("threaded", run_threaded),
+        ("bytecode-jit", run_bytecode_jit),
+        ("tiered", run_tiered),
+        ("c", run_c),
+        ("wasm", run_wasm),
+        ("asm", run_asm_att),
+        ("asm-intel", run_asm_intel),
+    ];
+    #[cfg(feature = "cranelift")]
+    backends.push(("cranelift", run_cranelift));
+    #[cfg(feature = "llvm")]
+    backends.push(("llvm", run_llvm));
+    backends
+}
+
+#[test]
+fn hello_world() {
+    let code = include_str!("../programs/hello_world.bf");
+    for (name, run) in backends() {
+        assert_eq!(
+            run(code, b"", EofPolicy::default()),
+            b"Hello World!\n",
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn optimized_patterns() {
+    // multiply loops in both directions, clear loop and scan loop, a cat
+    // loop, then the cells the multiply loops left
+    let code = ">+++[->++>+++<<]>>[-<<+>>]<[-]<[>]<<,[.[-],]>.>.>.";
+    for (name, run) in backends() {
+        assert_eq!(
+            run(code, b"abc", EofPolicy::Zero),
+            b"abc\x09\0\0",
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn eof_policies() {
+    // prints the cell after reading at the end of input, 7 before
+    let code = "+++++++,.";
+    for (name, run) in backends() {
+        for (policy, expected) in [
+            (EofPolicy::Zero, 0),
+            (EofPolicy::AllOnes, 255),
+            (EofPolicy::Unchanged, 7),
+        ] {
+            assert_eq!(run(code, b"", policy), [expected], "{} {:?}", name, policy);
+        }
+    }
+}
+
+#[test]
+fn wrapping_cells() {
+    // `-` on a zero cell, then runs longer than a cell holds
+    let code = format!("-.{}.{}.", "+".repeat(257), "-".repeat(258));
+    let interpreters: [(&str, Backend); 2] =
+        [("interpreter", run_interpreter), ("bytecode", run_bytecode)];
+    for (name, run) in backends().into_iter().chain(interpreters) {
+        assert_eq!(
+            run(&code, b"", EofPolicy::default()),
+            [255, 0, 254],
+            "{}",
+            name
+        );
+    }
+}
+
+fn optimized(code: &str) -> ByteCodeProgram {
+    let mut prog = Parser::parse_to_bytecode(code.to_owned());
+    prog.opt_pass_1();
+    prog.opt_pass_2();
+    prog
+}
+
+/// Runs `f` with an `Io` over `input` and returns what it wrote.
+fn with_io(
+    input: &[u8],
+    policy: EofPolicy,
+    f: impl FnOnce(&mut Io<&[u8], &mut Vec<u8>>),
+) -> Vec<u8> {
+    let mut output = vec![];
+    let mut io = Io::new(input, &mut output, policy);
+    f(&mut io);
+    io.finish().unwrap();
+    output
+}
+
+/// Drops the newline `eval_with_io` ends the output with. The interpreters
+/// read a line per `,`, so the cases with input leave them out.
+fn without_final_newline(mut output: Vec<u8>) -> Vec<u8> {
+    assert_eq!(output.pop(), Some(b'\n'));
+    output
+}
+
+fn run_interpreter(code: &str, mut input: &[u8], policy: EofPolicy) -> Vec<u8> {
+    let prog = Parser::parse(code.to_owned());
+    let mut output = vec![];
+    prog.eval_with_io(&mut (), &mut input, &mut output, policy);
+    without_final_newline(output)
+}
+
+fn run_bytecode(code: &str, mut input: &[u8], policy: EofPolicy) -> Vec<u8> {
+    let mut prog = Parser::parse_to_bytecode(code.to_owned());
+    prog.opt_pass_1();
+    let mut output = vec![];
+    prog.eval_with_io(&mut (), &mut input, &mut output, policy);
+    without_final_newline(output)
+}
+
+fn run_threaded(code: &str, input: &[u8], policy: EofPolicy) -> Vec<u8> {
+    let prog = optimized(code);
+    with_io(input, policy, |io| {
+        ThreadedCode::new(&prog).run(&mut [0; MEMORY_SIZE], io);
+    })
+}
+
+fn run_bytecode_jit(code: &str, input: &[u8], policy: EofPolicy) -> Vec<u8> {
+    let prog = Parser::parse_to_bytecode(code.to_owned());
+    let code = BytecodeJit::compile_program::<&[u8], &mut Vec<u8>>(&prog);
+    with_io(input, policy, |io| unsafe {
+        code.run(&mut [0; MEMORY_SIZE], 0, io as *mut _ as *mut u8);
+    })
+}
+
+/// Compiles every loop after its first iteration.
+fn run_tiered(code: &str, input: &[u8], policy: EofPolicy) -> Vec<u8> {
+    let prog = optimized(code);
+    with_io(input, policy, |io| {
+        TieredJit { threshold: 1 }.run(&prog, &mut [0; MEMORY_SIZE], io);
+    })
+}
+
+#[cfg(feature = "cranelift")]
+fn run_cranelift(code: &str, input: &[u8], policy: EofPolicy) -> Vec<u8> {
+    let prog = optimized(code);
+    with_io(input, policy, |io| {
+        let jit = CraneliftJit::new();
+        jit.execute(&prog, &mut [0; MEMORY_SIZE], io, &JitOptions::default());
+    })
+}
+
+#[cfg(feature = "llvm")]
+fn run_llvm(code: &str, input: &[u8], policy: EofPolicy) -> Vec<u8> {
+    let prog = optimized(code);
+    with_io(input, policy, |io| {
+        let jit = LlvmJit::for_host();
+        jit.execute(&prog, &mut [0; MEMORY_SIZE], io, &JitOptions::default());
+    })
+}
+
+fn run_c(code: &str, input: &[u8], policy: EofPolicy) -> Vec<u8> {
+    let options = JitOptions {
+        eof_policy: policy,
+        ..Default::default()
+    };
+    let c = CTranspiler::parse_and_transpile(code.to_owned(), &options, &COptions::default());
+    run_native("c", &c, &["-O1", "-Wall", "-Werror"], input)
+}
+
+fn run_asm_att(code: &str, input: &[u8], policy: EofPolicy) -> Vec<u8> {
+    run_asm(code, input, policy, AsmSyntax::Att)
+}
+
+fn run_asm_intel(code: &str, input: &[u8], policy: EofPolicy) -> Vec<u8> {
+    run_asm(code, input, policy, AsmSyntax::Intel)
+}
+
+fn run_asm(code: &str, input: &[u8], policy: EofPolicy, syntax: AsmSyntax) -> Vec<u8> {
+    let options = JitOptions {
+        eof_policy: policy,
+        ..Default::default()
+    };
+    let asm = AsmEmitter::emit(&optimized(code), code, &options, &AsmOptions { syntax });
+    run_native("s", &asm, &[], input)
+}
+
+/// A path in the temporary directory no other run uses.
+fn temp_path(extension: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!(
+        "bf-backends-{}-{}.{}",
+        std::process::id(),
+        n,
+        extension
+    ))
+}
+
+/// Builds `source` with `cc` and runs it on `input`.
+fn run_native(extension: &str, source: &str, cc_args: &[&str], input: &[u8]) -> Vec<u8> {
+    let path = temp_path(extension);
+    let exe = path.with_extension("");
+    std::fs::write(&path, source).unwrap();
+    let status = Command::new("cc")
+        .args(cc_args)
+        .arg("-o")
+        .arg(&exe)
+        .arg(&path)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let mut child = Command::new(&exe)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&exe).unwrap();
+    assert!(output.status.success());
+    output.stdout
+}
+
+struct Buffers {
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+/// Validates the module with wasmi and runs it, with `env.read` and
+/// `env.write` backed by byte buffers.
+fn run_wasm(code: &str, input: &[u8], policy: EofPolicy) -> Vec<u8> {
+    let options = JitOptions {
+        eof_policy: policy,
+        ..Default::default()
+    };
+    let wasm = WasmEmitter::parse_and_emit(code.to_owned(), &options);
+    let engine = Engine::default();
+    let module = Module::new(&engine, &wasm).unwrap();
+    let buffers = Buffers {
+        input: input.iter().rev().copied().collect(),
+        output: vec![],
+    };
+    let mut store = Store::new(&engine, buffers);
+    let mut linker = Linker::new(&engine);
+    linker
+        .func_wrap("env", "read", |mut caller: Caller<'_, Buffers>| {
+            caller.data_mut().input.pop().map_or(-1, i32::from)
+        })
+        .unwrap();
+    linker
+        .func_wrap("env", "write", |mut caller: Caller<'_, Buffers>, c: i32| {
+            caller.data_mut().output.push(c as u8)
+        })
+        .unwrap();
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .unwrap()
+        .start(&mut store)
+        .unwrap();
+    let run = instance.get_typed_func::<(), ()>(&store, "run").unwrap();
+    run.call(&mut store, ()).unwrap();
+    store.into_data().output
+}